@@ -0,0 +1,209 @@
+use std::{
+    collections::{HashMap, HashSet},
+    hash::Hash,
+    marker::PhantomData,
+    mem,
+};
+
+use crate::{
+    hash_wrap::{commit_behavior, SpecDrop},
+    transaction::AsyncTransaction,
+};
+
+#[allow(async_fn_in_trait)]
+pub trait HashLikeAsync<K, V> {
+    async fn insert(&mut self, k: K, v: V) -> Option<V>;
+    async fn remove(&mut self, k: &K) -> Option<V>;
+}
+
+// Edits are staged synchronously and only applying them awaits. Drop can't
+// await, so there is no implicit commit behaviour for this wrapper.
+pub struct AsyncHashWrap<'a, K, V, T, B = commit_behavior::PanicIfUnfinalised>
+where
+    AsyncHashWrap<'a, K, V, T, B>: SpecDrop,
+    K: Eq + Hash,
+    T: HashLikeAsync<K, V>,
+    B: commit_behavior::Behavior,
+{
+    inner: &'a mut T,
+    added: HashMap<K, V>,
+    removed: HashSet<K>,
+    commit_behaviour: PhantomData<B>,
+    finalised: bool,
+}
+
+impl<'a, K, V, T> AsyncHashWrap<'a, K, V, T>
+where
+    K: Eq + Hash,
+    T: HashLikeAsync<K, V>,
+{
+    pub fn new(store: &'a mut T) -> Self {
+        AsyncHashWrap {
+            inner: store,
+            added: HashMap::new(),
+            removed: HashSet::new(),
+            commit_behaviour: PhantomData,
+            finalised: false,
+        }
+    }
+}
+
+impl<'a, K, V, T, B> AsyncHashWrap<'a, K, V, T, B>
+where
+    AsyncHashWrap<'a, K, V, T, B>: SpecDrop,
+    K: Eq + Hash,
+    T: HashLikeAsync<K, V>,
+    B: commit_behavior::Behavior,
+{
+    // The wrap counts as finalised once applying starts, so a commit future
+    // that is dropped part way leaves whatever was applied so far in place.
+    pub async fn commit(mut self) {
+        self.finalised = true;
+        for k in mem::take(&mut self.removed) {
+            self.inner.remove(&k).await;
+        }
+        for (k, v) in mem::take(&mut self.added) {
+            self.inner.insert(k, v).await;
+        }
+    }
+
+    fn _rollback(&mut self) {
+        self.finalised = true;
+    }
+
+    pub fn rollback(mut self) {
+        self._rollback()
+    }
+
+    // The store can only be read by awaiting, so staging reports the value it
+    // displaces from the staging area and `None` for a key it hasn't staged.
+    pub fn insert(&mut self, k: K, v: V) -> Option<V> {
+        self.removed.remove(&k);
+        self.added.insert(k, v)
+    }
+
+    pub fn remove(&mut self, k: &K) -> Option<V>
+    where
+        K: Clone,
+    {
+        self.removed.insert(k.clone());
+        self.added.remove(k)
+    }
+
+    pub fn staged_insertions(&self) -> &HashMap<K, V> {
+        &self.added
+    }
+
+    pub fn staged_removals(&self) -> &HashSet<K> {
+        &self.removed
+    }
+}
+
+impl<'a, K, V, T, B> AsyncTransaction for AsyncHashWrap<'a, K, V, T, B>
+where
+    AsyncHashWrap<'a, K, V, T, B>: SpecDrop,
+    K: Eq + Hash,
+    T: HashLikeAsync<K, V>,
+    B: commit_behavior::Behavior,
+{
+    async fn commit(self) {
+        AsyncHashWrap::commit(self).await
+    }
+
+    async fn rollback(self) {
+        AsyncHashWrap::rollback(self)
+    }
+}
+
+impl_into_behavior! {
+    AsyncHashWrap<'a, K, V, T> { take: added, removed; }
+    where { K: Eq + Hash, T: HashLikeAsync<K, V> }
+    into_implicit_rollback => ImplicitRollback;
+    into_panicking => PanicIfUnfinalised;
+}
+
+impl_spec_drop! {
+    AsyncHashWrap<'a, K, V, T> where { K: Eq + Hash, T: HashLikeAsync<K, V> }
+    ImplicitRollback => _rollback;
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        future::Future,
+        pin::pin,
+        sync::Arc,
+        task::{Context, Poll, Wake, Waker},
+        thread,
+    };
+
+    use super::*;
+
+    #[derive(Debug, PartialEq)]
+    enum Op {
+        Insert(i32, String),
+        Remove(i32),
+    }
+
+    #[derive(Default)]
+    struct MockStore {
+        map: HashMap<i32, String>,
+        log: Vec<Op>,
+    }
+
+    impl HashLikeAsync<i32, String> for MockStore {
+        async fn insert(&mut self, k: i32, v: String) -> Option<String> {
+            self.log.push(Op::Insert(k, v.clone()));
+            self.map.insert(k, v)
+        }
+
+        async fn remove(&mut self, k: &i32) -> Option<String> {
+            self.log.push(Op::Remove(*k));
+            self.map.remove(k)
+        }
+    }
+
+    struct Unpark(thread::Thread);
+
+    impl Wake for Unpark {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    fn block_on<F: Future>(f: F) -> F::Output {
+        let waker = Waker::from(Arc::new(Unpark(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        let mut f = pin!(f);
+        loop {
+            match f.as_mut().poll(&mut cx) {
+                Poll::Ready(v) => return v,
+                Poll::Pending => thread::park(),
+            }
+        }
+    }
+
+    #[test]
+    fn async_commit() {
+        let mut store = MockStore::default();
+        store.map.insert(0, "Zero".to_string());
+        let mut wrap = AsyncHashWrap::new(&mut store);
+        assert_eq!(wrap.insert(1, "Uno".to_string()), None);
+        assert_eq!(wrap.insert(1, "One".to_string()), Some("Uno".to_string()));
+        assert_eq!(wrap.remove(&0), None);
+        assert_eq!(wrap.staged_insertions().len(), 1);
+        block_on(wrap.commit());
+        assert_eq!(
+            store.log,
+            vec![Op::Remove(0), Op::Insert(1, "One".to_string())]
+        );
+        assert_eq!(store.map[&1], "One");
+        assert!(!store.map.contains_key(&0));
+
+        let mut wrap = AsyncHashWrap::new(&mut store);
+        wrap.insert(2, "Two".to_string());
+        block_on(AsyncTransaction::rollback(wrap));
+        drop(AsyncHashWrap::new(&mut store).into_implicit_rollback());
+        assert_eq!(store.log.len(), 2);
+    }
+}
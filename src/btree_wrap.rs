@@ -0,0 +1,240 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    iter,
+    marker::PhantomData,
+    mem,
+    ops::RangeBounds,
+};
+
+use crate::{
+    hash_wrap::{commit_behavior, SpecDrop},
+    transaction::Transaction,
+};
+
+pub struct BTreeMapWrap<'a, K, V, B = commit_behavior::PanicIfUnfinalised>
+where
+    BTreeMapWrap<'a, K, V, B>: SpecDrop,
+    K: Ord,
+    B: commit_behavior::Behavior,
+{
+    inner: &'a mut BTreeMap<K, V>,
+    added: BTreeMap<K, V>,
+    removed: BTreeSet<K>,
+    commit_behaviour: PhantomData<B>,
+    finalised: bool,
+}
+
+impl<'a, K, V> BTreeMapWrap<'a, K, V>
+where
+    K: Ord,
+{
+    pub fn new(map: &'a mut BTreeMap<K, V>) -> Self {
+        BTreeMapWrap {
+            inner: map,
+            added: BTreeMap::new(),
+            removed: BTreeSet::new(),
+            commit_behaviour: PhantomData,
+            finalised: false,
+        }
+    }
+}
+
+impl<'a, K, V, B> BTreeMapWrap<'a, K, V, B>
+where
+    BTreeMapWrap<'a, K, V, B>: SpecDrop,
+    K: Ord + Clone,
+    V: Clone,
+    B: commit_behavior::Behavior,
+{
+    fn _commit(&mut self) {
+        for k in mem::take(&mut self.removed) {
+            self.inner.remove(&k);
+        }
+        self.inner.append(&mut self.added);
+        self.finalised = true;
+    }
+
+    pub fn commit(mut self) {
+        self._commit()
+    }
+
+    fn _rollback(&mut self) {
+        self.finalised = true;
+    }
+
+    pub fn rollback(mut self) {
+        self._rollback()
+    }
+
+    pub fn contains_key(&self, k: &K) -> bool {
+        !self.removed.contains(k) && (self.added.contains_key(k) || self.inner.contains_key(k))
+    }
+
+    pub fn get(&self, k: &K) -> Option<&V> {
+        if self.added.contains_key(k) {
+            self.added.get(k)
+        } else {
+            if self.removed.contains(k) {
+                None
+            } else {
+                self.inner.get(k)
+            }
+        }
+    }
+
+    pub fn insert(&mut self, k: K, v: V) -> Option<V> {
+        if self.added.contains_key(&k) {
+            self.added.insert(k, v)
+        } else {
+            let ret = if self.removed.remove(&k) {
+                None
+            } else {
+                self.inner.get(&k).cloned()
+            };
+            self.added.insert(k, v);
+            ret
+        }
+    }
+
+    pub fn remove(&mut self, k: &K) -> Option<V> {
+        if self.added.contains_key(k) {
+            if self.inner.contains_key(k) {
+                self.removed.insert(k.clone());
+            }
+            self.added.remove(k)
+        } else {
+            if self.removed.contains(k) {
+                None
+            } else {
+                let ret = self.inner.get(k).cloned();
+                if ret.is_some() {
+                    self.removed.insert(k.clone());
+                }
+                ret
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        let removed = self
+            .removed
+            .iter()
+            .filter(|k| self.inner.contains_key(k))
+            .count();
+        let added = self
+            .added
+            .keys()
+            .filter(|k| !self.inner.contains_key(k))
+            .count();
+        self.inner.len() - removed + added
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.range(..)
+    }
+
+    pub fn range<R>(&self, range: R) -> impl Iterator<Item = (&K, &V)>
+    where
+        R: RangeBounds<K> + Clone,
+    {
+        let (added, removed) = (&self.added, &self.removed);
+        let mut staged = added.range(range.clone()).peekable();
+        let mut original = self
+            .inner
+            .range(range)
+            .filter(move |(k, _)| !removed.contains(k) && !added.contains_key(k))
+            .peekable();
+        iter::from_fn(move || match (original.peek(), staged.peek()) {
+            (Some(o), Some(s)) if o.0 < s.0 => original.next(),
+            (Some(_), None) => original.next(),
+            _ => staged.next(),
+        })
+    }
+}
+
+impl<'a, K, V, B> Transaction for BTreeMapWrap<'a, K, V, B>
+where
+    BTreeMapWrap<'a, K, V, B>: SpecDrop,
+    K: Ord + Clone,
+    V: Clone,
+    B: commit_behavior::Behavior,
+{
+    fn commit(self) {
+        BTreeMapWrap::commit(self)
+    }
+
+    fn rollback(self) {
+        BTreeMapWrap::rollback(self)
+    }
+}
+
+impl_into_behavior! {
+    BTreeMapWrap<'a, K, V> { take: added, removed; }
+    where { K: Ord + Clone, V: Clone }
+    into_implicit_commit => ImplicitCommit;
+    into_implicit_rollback => ImplicitRollback;
+    into_panicking => PanicIfUnfinalised;
+}
+
+impl_spec_drop! {
+    BTreeMapWrap<'a, K, V> where { K: Ord }
+    ImplicitCommit => _commit where { K: Clone, V: Clone };
+    ImplicitRollback => _rollback where { K: Clone, V: Clone };
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn get_map() -> BTreeMap<i32, String> {
+        let mut m = BTreeMap::new();
+        m.insert(0, "Zero".to_string());
+        m.insert(2, "Two".to_string());
+        m.insert(4, "Four".to_string());
+        m.insert(6, "Six".to_string());
+        m
+    }
+
+    #[test]
+    fn range_query() {
+        let mut map = get_map();
+        let mut wrap = BTreeMapWrap::new(&mut map);
+        wrap.insert(3, "Three".to_string());
+        wrap.insert(4, "Vier".to_string());
+        wrap.insert(7, "Seven".to_string());
+        wrap.remove(&2);
+        let keys: Vec<_> = wrap.range(1..7).map(|(k, v)| (*k, v.as_str())).collect();
+        assert_eq!(keys, vec![(3, "Three"), (4, "Vier"), (6, "Six")]);
+        let keys: Vec<_> = wrap.iter().map(|(k, _)| *k).collect();
+        assert_eq!(keys, vec![0, 3, 4, 6, 7]);
+        assert_eq!(wrap.len(), 5);
+        wrap.commit();
+        let keys: Vec<_> = map.keys().cloned().collect();
+        assert_eq!(keys, vec![0, 3, 4, 6, 7]);
+        assert_eq!(map[&4], "Vier");
+    }
+
+    #[test]
+    fn staged_edits() {
+        let mut map = get_map();
+        let mut wrap = BTreeMapWrap::new(&mut map);
+        assert_eq!(wrap.insert(2, "Deux".to_string()), Some("Two".to_string()));
+        assert_eq!(wrap.remove(&2), Some("Deux".to_string()));
+        assert_eq!(wrap.remove(&2), None);
+        assert_eq!(wrap.insert(2, "Dos".to_string()), None);
+        assert_eq!(wrap.get(&2), Some(&"Dos".to_string()));
+        assert_eq!(wrap.remove(&9), None);
+        assert!(!wrap.contains_key(&9));
+        wrap.rollback();
+        assert_eq!(map, get_map());
+
+        let mut wrap = BTreeMapWrap::new(&mut map);
+        wrap.remove(&0);
+        drop(wrap.into_implicit_commit());
+        assert!(!map.contains_key(&0));
+    }
+}
@@ -1,14 +1,28 @@
 use std::{
+    marker::PhantomData,
     mem,
     ops::{Deref, DerefMut},
 };
 
-pub struct GenericWrap<'a, T>
+#[cfg(feature = "serde")]
+use serde::{Serialize, Serializer};
+
+use crate::{
+    hash_wrap::{commit_behavior, SavepointError, SpecDrop},
+    transaction::Transaction,
+};
+
+pub struct GenericWrap<'a, T, B = commit_behavior::PanicIfUnfinalised>
     where
+        GenericWrap<'a, T, B>: SpecDrop,
         T: Clone,
+        B: commit_behavior::Behavior,
 {
     inner: &'a mut T,
     copy: Option<T>,
+    savepoints: Vec<Option<T>>,
+    commit_behaviour: PhantomData<B>,
+    finalised: bool,
 }
 
 impl<'a, T> GenericWrap<'a, T>
@@ -19,45 +33,554 @@ impl<'a, T> GenericWrap<'a, T>
         GenericWrap {
             inner: val,
             copy: None,
+            savepoints: Vec::new(),
+            commit_behaviour: PhantomData,
+            finalised: false,
         }
     }
 
-    pub fn replace(val: Self) -> Option<T> {
-        let copy = val.copy;
-        let inner = val.inner;
+    #[deprecated(note = "Use commit instead.")]
+    pub fn replace(mut val: Self) -> Option<T> {
+        val.finalised = true;
+        let copy = val.copy.take();
+        let inner = &mut *val.inner;
         copy.map(|v| mem::replace(inner, v))
     }
 
-    pub fn discard(val: Self) -> Option<T> {
-        val.copy
+    #[deprecated(note = "Use rollback instead.")]
+    pub fn discard(mut val: Self) -> Option<T> {
+        val.finalised = true;
+        val.copy.take()
     }
 }
 
-impl<'a, T> Deref for GenericWrap<'a, T>
+impl<'a, T, B> GenericWrap<'a, T, B>
     where
-        T: Clone
+        GenericWrap<'a, T, B>: SpecDrop,
+        T: Clone,
+        B: commit_behavior::Behavior,
+{
+    fn _commit(&mut self) -> bool {
+        self.finalised = true;
+        if let Some(v) = self.copy.take() {
+            *self.inner = v;
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn commit(mut self) {
+        self._commit();
+    }
+
+    pub fn commit_reported(mut self) -> bool {
+        self._commit()
+    }
+
+    pub fn try_commit<E, F>(mut self, validate: F) -> Result<(), E>
+        where
+            F: FnOnce(&T) -> Result<(), E>,
+    {
+        match validate(&*self) {
+            Ok(()) => {
+                self._commit();
+                Ok(())
+            }
+            Err(e) => {
+                self._rollback();
+                Err(e)
+            }
+        }
+    }
+
+    fn _rollback(&mut self) {
+        self.copy = None;
+        self.finalised = true;
+    }
+
+    pub fn rollback(mut self) {
+        self._rollback()
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        self.copy.is_some()
+    }
+
+    pub fn is_changed(&self) -> bool
+        where
+            T: PartialEq,
+    {
+        match &self.copy {
+            Some(v) => *v != *self.inner,
+            None => false,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.copy = None;
+    }
+
+    pub fn original(&self) -> &T {
+        &*self.inner
+    }
+
+    pub fn current(&self) -> &T {
+        self.deref()
+    }
+
+    pub fn apply_from<'b, B2>(&mut self, other: &GenericWrap<'b, T, B2>)
+        where
+            GenericWrap<'b, T, B2>: SpecDrop,
+            B2: commit_behavior::Behavior,
+    {
+        if let Some(v) = &other.copy {
+            self.copy = Some(v.clone());
+        }
+    }
+
+    pub fn snapshot(&self) -> T {
+        self.deref().clone()
+    }
+
+    pub fn savepoint(&mut self) {
+        self.savepoints.push(self.copy.clone());
+    }
+
+    pub fn rollback_to_savepoint(&mut self) -> Result<(), SavepointError> {
+        self.copy = self.savepoints.pop().ok_or(SavepointError)?;
+        Ok(())
+    }
+}
+
+impl<'a, T, B> Deref for GenericWrap<'a, T, B>
+    where
+        GenericWrap<'a, T, B>: SpecDrop,
+        T: Clone,
+        B: commit_behavior::Behavior,
 {
     type Target = T;
 
     fn deref(&self) -> &Self::Target {
         match &self.copy {
             Some(v) => v,
-            None => &self.inner,
+            None => self.inner,
         }
     }
 }
 
-impl<'a, T> DerefMut for GenericWrap<'a, T>
+impl<'a, T, B> DerefMut for GenericWrap<'a, T, B>
     where
-        T: Clone
+        GenericWrap<'a, T, B>: SpecDrop,
+        T: Clone,
+        B: commit_behavior::Behavior,
 {
     fn deref_mut(&mut self) -> &mut Self::Target {
-        match &mut self.copy {
-            Some(v) => v,
-            c @ None => {
-                mem::replace(c, Some(self.inner.clone()));
-                c.as_mut().unwrap()
-            },
+        match self.copy {
+            Some(ref mut v) => v,
+            None => self.copy.insert(self.inner.clone()),
+        }
+    }
+}
+
+impl<'a, T, B> AsRef<T> for GenericWrap<'a, T, B>
+    where
+        GenericWrap<'a, T, B>: SpecDrop,
+        T: Clone,
+        B: commit_behavior::Behavior,
+{
+    fn as_ref(&self) -> &T {
+        self.deref()
+    }
+}
+
+impl<'a, T, B> AsMut<T> for GenericWrap<'a, T, B>
+    where
+        GenericWrap<'a, T, B>: SpecDrop,
+        T: Clone,
+        B: commit_behavior::Behavior,
+{
+    fn as_mut(&mut self) -> &mut T {
+        self.deref_mut()
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'a, T, B> Serialize for GenericWrap<'a, T, B>
+    where
+        GenericWrap<'a, T, B>: SpecDrop,
+        T: Clone + Serialize,
+        B: commit_behavior::Behavior,
+{
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where
+            S: Serializer,
+    {
+        self.deref().serialize(serializer)
+    }
+}
+
+impl<'a, T, B> Transaction for GenericWrap<'a, T, B>
+    where
+        GenericWrap<'a, T, B>: SpecDrop,
+        T: Clone,
+        B: commit_behavior::Behavior,
+{
+    fn commit(self) {
+        GenericWrap::commit(self)
+    }
+
+    fn rollback(self) {
+        GenericWrap::rollback(self)
+    }
+}
+
+impl_into_behavior! {
+    GenericWrap<'a, T> { take: copy, savepoints; }
+    where { T: Clone }
+    into_implicit_commit => ImplicitCommit;
+    into_implicit_rollback => ImplicitRollback;
+    into_panicking => PanicIfUnfinalised;
+}
+
+impl_spec_drop! {
+    GenericWrap<'a, T> where { T: Clone }
+    ImplicitCommit => _commit;
+    ImplicitRollback => _rollback;
+}
+
+pub struct CopyWrap<'a, T, B = commit_behavior::PanicIfUnfinalised>
+    where
+        CopyWrap<'a, T, B>: SpecDrop,
+        T: Copy,
+        B: commit_behavior::Behavior,
+{
+    inner: &'a mut T,
+    copy: T,
+    commit_behaviour: PhantomData<B>,
+    finalised: bool,
+}
+
+impl<'a, T> CopyWrap<'a, T>
+    where
+        T: Copy
+{
+    pub fn new(val: &'a mut T) -> Self {
+        CopyWrap {
+            copy: *val,
+            inner: val,
+            commit_behaviour: PhantomData,
+            finalised: false,
         }
     }
-}
\ No newline at end of file
+}
+
+impl<'a, T, B> CopyWrap<'a, T, B>
+    where
+        CopyWrap<'a, T, B>: SpecDrop,
+        T: Copy,
+        B: commit_behavior::Behavior,
+{
+    fn _commit(&mut self) {
+        *self.inner = self.copy;
+        self.finalised = true;
+    }
+
+    pub fn commit(mut self) {
+        self._commit()
+    }
+
+    fn _rollback(&mut self) {
+        self.finalised = true;
+    }
+
+    pub fn rollback(mut self) {
+        self._rollback()
+    }
+}
+
+impl<'a, T, B> Deref for CopyWrap<'a, T, B>
+    where
+        CopyWrap<'a, T, B>: SpecDrop,
+        T: Copy,
+        B: commit_behavior::Behavior,
+{
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        &self.copy
+    }
+}
+
+impl<'a, T, B> DerefMut for CopyWrap<'a, T, B>
+    where
+        CopyWrap<'a, T, B>: SpecDrop,
+        T: Copy,
+        B: commit_behavior::Behavior,
+{
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.copy
+    }
+}
+
+impl<'a, T, B> Transaction for CopyWrap<'a, T, B>
+    where
+        CopyWrap<'a, T, B>: SpecDrop,
+        T: Copy,
+        B: commit_behavior::Behavior,
+{
+    fn commit(self) {
+        CopyWrap::commit(self)
+    }
+
+    fn rollback(self) {
+        CopyWrap::rollback(self)
+    }
+}
+
+impl_into_behavior! {
+    CopyWrap<'a, T> { copy: copy; }
+    where { T: Copy }
+    into_implicit_commit => ImplicitCommit;
+    into_implicit_rollback => ImplicitRollback;
+    into_panicking => PanicIfUnfinalised;
+}
+
+impl_spec_drop! {
+    CopyWrap<'a, T> where { T: Copy }
+    ImplicitCommit => _commit;
+    ImplicitRollback => _rollback;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn commit_and_rollback() {
+        let mut val = vec![1, 2, 3];
+        let mut wrap = GenericWrap::new(&mut val);
+        wrap.push(4);
+        wrap.commit();
+        assert_eq!(val, vec![1, 2, 3, 4]);
+
+        let wrap = GenericWrap::new(&mut val);
+        assert_eq!(wrap.len(), 4);
+        wrap.commit();
+        assert_eq!(val, vec![1, 2, 3, 4]);
+
+        let mut wrap = GenericWrap::new(&mut val);
+        wrap.clear();
+        wrap.rollback();
+        assert_eq!(val, vec![1, 2, 3, 4]);
+
+        let wrap = GenericWrap::new(&mut val);
+        wrap.rollback();
+        assert_eq!(val, vec![1, 2, 3, 4]);
+    }
+
+    #[test]
+    #[should_panic(expected = "without calling commit or rollback")]
+    fn drop_unfinalised() {
+        let mut val = vec![1, 2, 3];
+        let mut wrap = GenericWrap::new(&mut val);
+        wrap.push(4);
+    }
+
+    #[test]
+    fn dirty_and_changed() {
+        let mut val = vec![1, 2, 3];
+        let mut wrap = GenericWrap::new(&mut val);
+        assert!(!wrap.is_dirty());
+        assert!(!wrap.is_changed());
+        wrap.deref_mut();
+        assert!(wrap.is_dirty());
+        assert!(!wrap.is_changed());
+        wrap.push(4);
+        assert!(wrap.is_changed());
+        wrap.rollback();
+    }
+
+    #[test]
+    fn reset_edits() {
+        let mut val = vec![1, 2, 3];
+        let mut wrap = GenericWrap::new(&mut val);
+        for i in 0..3 {
+            wrap.push(i);
+            wrap.reset();
+            assert_eq!(*wrap, vec![1, 2, 3]);
+            assert!(!wrap.is_dirty());
+        }
+        wrap.push(4);
+        wrap.commit();
+        assert_eq!(val, vec![1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn original_and_current() {
+        let mut val = vec![1, 2, 3];
+        let mut wrap = GenericWrap::new(&mut val);
+        assert_eq!(wrap.original(), wrap.current());
+        wrap.push(4);
+        assert_eq!(*wrap.original(), vec![1, 2, 3]);
+        assert_eq!(*wrap.current(), vec![1, 2, 3, 4]);
+        wrap.commit();
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    struct Point {
+        x: i64,
+        y: i64,
+    }
+
+    #[test]
+    fn copy_fast_path() {
+        let mut val = Point { x: 1, y: 2 };
+        let mut wrap = CopyWrap::new(&mut val);
+        wrap.x += 10;
+        assert_eq!(*wrap, Point { x: 11, y: 2 });
+        wrap.rollback();
+        assert_eq!(val, Point { x: 1, y: 2 });
+
+        let mut wrap = CopyWrap::new(&mut val);
+        wrap.y = 5;
+        wrap.commit();
+        assert_eq!(val, Point { x: 1, y: 5 });
+
+        let mut wrap = CopyWrap::new(&mut val).into_implicit_commit();
+        wrap.x = 0;
+        drop(wrap);
+        assert_eq!(val, Point { x: 0, y: 5 });
+    }
+
+    #[test]
+    fn validated_commit() {
+        let mut val = vec![1, 2, 3];
+        let mut wrap = GenericWrap::new(&mut val);
+        wrap.clear();
+        let res = wrap.try_commit(|v| if v.is_empty() { Err("empty") } else { Ok(()) });
+        assert_eq!(res, Err("empty"));
+        assert_eq!(val, vec![1, 2, 3]);
+
+        let mut wrap = GenericWrap::new(&mut val);
+        wrap.push(4);
+        let res = wrap.try_commit(|v| if v.is_empty() { Err("empty") } else { Ok(()) });
+        assert_eq!(res, Ok(()));
+        assert_eq!(val, vec![1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn commit_reports_write_back() {
+        let mut val = vec![1, 2, 3];
+        let mut wrap = GenericWrap::new(&mut val);
+        wrap.push(4);
+        assert!(wrap.commit_reported());
+        assert_eq!(val, vec![1, 2, 3, 4]);
+
+        let wrap = GenericWrap::new(&mut val);
+        assert!(!wrap.commit_reported());
+        assert_eq!(val, vec![1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn savepoint_stack() {
+        let mut val = vec![1, 2, 3];
+        let mut wrap = GenericWrap::new(&mut val);
+        wrap.savepoint();
+        wrap.push(4);
+        wrap.savepoint();
+        wrap.push(5);
+        assert_eq!(wrap.rollback_to_savepoint(), Ok(()));
+        assert_eq!(*wrap, vec![1, 2, 3, 4]);
+        assert_eq!(wrap.rollback_to_savepoint(), Ok(()));
+        assert_eq!(*wrap, vec![1, 2, 3]);
+        assert!(!wrap.is_dirty());
+        wrap.push(6);
+        assert_eq!(wrap.rollback_to_savepoint(), Err(SavepointError));
+        wrap.savepoint();
+        wrap.clear();
+        assert_eq!(wrap.rollback_to_savepoint(), Ok(()));
+        wrap.commit();
+        assert_eq!(val, vec![1, 2, 3, 6]);
+    }
+
+    #[test]
+    fn snapshot_edit() {
+        let mut val = vec![1, 2, 3];
+        let mut wrap = GenericWrap::new(&mut val);
+        wrap.push(4);
+        let snap = wrap.snapshot();
+        wrap.push(5);
+        assert_eq!(snap, vec![1, 2, 3, 4]);
+        wrap.rollback();
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serialize_edit() {
+        let mut val = vec![1, 2, 3];
+        let mut wrap = GenericWrap::new(&mut val);
+        wrap.push(4);
+        let json = serde_json::to_string(&wrap).unwrap();
+        assert_eq!(json, "[1,2,3,4]");
+        let restored: Vec<i32> = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored, wrap.snapshot());
+        wrap.rollback();
+    }
+
+    #[test]
+    fn as_ref_and_as_mut() {
+        fn append<V: AsMut<Vec<i32>>>(mut v: V) {
+            v.as_mut().push(4);
+        }
+
+        let mut val = vec![1, 2, 3];
+        let mut wrap = GenericWrap::new(&mut val);
+        let r: &Vec<i32> = wrap.as_ref();
+        assert_eq!(*r, vec![1, 2, 3]);
+        append(&mut wrap);
+        assert!(wrap.is_dirty());
+        let r: &Vec<i32> = wrap.as_ref();
+        assert_eq!(*r, vec![1, 2, 3, 4]);
+        wrap.commit();
+        assert_eq!(val, vec![1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn apply_sibling_edit() {
+        let mut val = vec![1, 2, 3];
+        let mut other_val = val.clone();
+        let mut other = GenericWrap::new(&mut other_val);
+        other.push(4);
+        let mut clean_val = vec![9];
+        let mut wrap = GenericWrap::new(&mut val);
+        let clean = GenericWrap::new(&mut clean_val);
+        wrap.apply_from(&clean);
+        assert!(!wrap.is_dirty());
+        clean.rollback();
+        wrap.apply_from(&other);
+        other.rollback();
+        wrap.commit();
+        assert_eq!(val, vec![1, 2, 3, 4]);
+        assert_eq!(other_val, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn implicit_behaviors() {
+        let mut val = vec![1, 2, 3];
+        let mut wrap = GenericWrap::new(&mut val);
+        wrap.push(4);
+        drop(wrap.into_implicit_commit());
+        assert_eq!(val, vec![1, 2, 3, 4]);
+
+        let mut wrap = GenericWrap::new(&mut val).into_implicit_rollback();
+        wrap.clear();
+        drop(wrap);
+        assert_eq!(val, vec![1, 2, 3, 4]);
+
+        let mut wrap = GenericWrap::new(&mut val).into_implicit_commit();
+        wrap.pop();
+        wrap.into_panicking().rollback();
+        assert_eq!(val, vec![1, 2, 3, 4]);
+    }
+}
@@ -1,13 +1,21 @@
 use std::{
-    collections::{HashMap, HashSet},
+    borrow::Borrow,
+    collections::{hash_map::Entry, HashMap, HashSet},
+    fmt,
     hash::Hash,
+    iter,
     marker::PhantomData,
-    ops::Index,
+    mem,
+    ops::{Index, IndexMut},
 };
 
 use hashlike::HashLike;
 #[cfg(feature = "multikey")]
 use multikeymap::MultiKeyMap;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use crate::transaction::{Transaction, TwoPhase};
 
 pub mod commit_behavior {
     mod sealed {
@@ -17,13 +25,167 @@ pub mod commit_behavior {
         impl Sealed for ImplicitRollback {}
         impl Sealed for ImplicitCommit {}
     }
-    pub trait Behavior: sealed::Sealed {}
+    pub trait Behavior: sealed::Sealed {
+        const NAME: &'static str;
+    }
     pub struct PanicIfUnfinalised;
     pub struct ImplicitRollback;
     pub struct ImplicitCommit;
-    impl Behavior for PanicIfUnfinalised {}
-    impl Behavior for ImplicitRollback {}
-    impl Behavior for ImplicitCommit {}
+    impl Behavior for PanicIfUnfinalised {
+        const NAME: &'static str = "PanicIfUnfinalised";
+    }
+    impl Behavior for ImplicitRollback {
+        const NAME: &'static str = "ImplicitRollback";
+    }
+    impl Behavior for ImplicitCommit {
+        const NAME: &'static str = "ImplicitCommit";
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CommitReport {
+    pub inserted: usize,
+    pub overwritten: usize,
+    pub removed: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SavepointId(pub(crate) usize);
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Changeset<K, V>
+where
+    K: Eq + Hash,
+{
+    pub added: HashMap<K, V>,
+    pub removed: HashSet<K>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MapDiff<K, V>
+where
+    K: Eq + Hash,
+{
+    pub only_in_view: HashMap<K, V>,
+    pub only_in_other: HashMap<K, V>,
+    pub differing: HashMap<K, (V, V)>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source<T> {
+    Staged(T),
+    Original(T),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConflictError<K> {
+    pub keys: Vec<K>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyError {
+    Removed,
+    Absent,
+}
+
+// The savepoint was never taken, or was invalidated by rolling back past it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SavepointError;
+
+// Every error the wrappers can report, so callers avoiding the panicking APIs
+// can propagate them all with `?`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TxError<K> {
+    Unfinalised,
+    KeyRemoved,
+    KeyAbsent,
+    Conflict(Vec<K>),
+    OutstandingLoans(Vec<K>),
+    UnknownSavepoint,
+}
+
+impl<K> From<KeyError> for TxError<K> {
+    fn from(e: KeyError) -> Self {
+        match e {
+            KeyError::Removed => TxError::KeyRemoved,
+            KeyError::Absent => TxError::KeyAbsent,
+        }
+    }
+}
+
+impl<K> From<ConflictError<K>> for TxError<K> {
+    fn from(e: ConflictError<K>) -> Self {
+        TxError::Conflict(e.keys)
+    }
+}
+
+impl<K> From<SavepointError> for TxError<K> {
+    fn from(_: SavepointError) -> Self {
+        TxError::UnknownSavepoint
+    }
+}
+
+type ResolveFn<'f, K, V> = dyn Fn(&K, &V, V) -> V + 'f;
+
+// How a commit resolves a staged edit to a key that changed in the backing map
+// after the wrap was opened. `Custom` is handed the key, the live value and the
+// staged value, as with `commit_with`.
+pub enum MergeStrategy<'f, K, V> {
+    OursWins,
+    TheirsWins,
+    Custom(Box<ResolveFn<'f, K, V>>),
+}
+
+impl<K, V> fmt::Debug for MergeStrategy<'_, K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MergeStrategy::OursWins => f.write_str("OursWins"),
+            MergeStrategy::TheirsWins => f.write_str("TheirsWins"),
+            MergeStrategy::Custom(_) => f.write_str("Custom"),
+        }
+    }
+}
+
+pub trait Generation {
+    fn generation(&self) -> u64;
+}
+
+// How a single key was staged, recorded before each logged edit so it can be
+// undone by putting the key back the way it was.
+#[derive(Debug, Clone)]
+enum Staged<V> {
+    Added(V),
+    Removed,
+    Clean,
+}
+
+// Every key an edit touched, each with how it was staged beforehand.
+type Edit<K, V> = Vec<(K, Staged<V>)>;
+
+// A copy of the staging, along with the label it was given if it was taken as
+// a checkpoint.
+type Savepoint<K, V> = (SavepointId, Option<String>, HashMap<K, V>, HashSet<K>);
+
+type HookFn<'a, K, V> = dyn FnOnce(&HashMap<K, V>, &HashSet<K>) + 'a;
+
+type StagingCopy<K, V> = (HashMap<K, V>, HashSet<K>);
+
+type PendingHook<'a, K, V> = (Box<HookFn<'a, K, V>>, StagingCopy<K, V>);
+
+type CopyFn<K, V> = fn(&HashMap<K, V>, &HashSet<K>) -> StagingCopy<K, V>;
+
+// Applying the staging moves the values out of it, so the hook keeps a way to
+// copy the staging beforehand and is handed the copy once it has landed.
+struct CommitHook<'a, K, V> {
+    hook: Box<HookFn<'a, K, V>>,
+    copy: CopyFn<K, V>,
+}
+
+impl<K, V> fmt::Debug for CommitHook<'_, K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("CommitHook")
+    }
 }
 
 #[derive(Debug)]
@@ -37,10 +199,102 @@ where
     inner: &'a mut T,
     added: HashMap<K, V>,
     removed: HashSet<K>,
+    savepoints: Vec<Savepoint<K, V>>,
+    next_savepoint: usize,
+    undo_log: Vec<Edit<K, V>>,
+    redo_log: Vec<Edit<K, V>>,
+    undoable: bool,
+    baseline: Option<T>,
+    generation: Option<u64>,
+    commit_hook: Option<CommitHook<'a, K, V>>,
     commit_behaviour: PhantomData<B>,
     finalised: bool,
 }
 
+pub fn diff<K, V>(base: &HashMap<K, V>, target: &HashMap<K, V>) -> Changeset<K, V>
+where
+    K: Eq + Hash + Clone,
+    V: Clone + PartialEq,
+{
+    Changeset {
+        added: target
+            .iter()
+            .filter(|(k, v)| base.get(k) != Some(v))
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect(),
+        removed: base
+            .keys()
+            .filter(|k| !target.contains_key(k))
+            .cloned()
+            .collect(),
+    }
+}
+
+pub fn apply_diff<K, V>(map: &mut HashMap<K, V>, cs: &Changeset<K, V>)
+where
+    K: Eq + Hash + Clone,
+    V: Clone,
+{
+    for k in &cs.removed {
+        map.remove(k);
+    }
+    map.extend(cs.added.iter().map(|(k, v)| (k.clone(), v.clone())));
+}
+
+// `f` is handed the store as it stands before each attempt and builds the
+// changeset to apply. The generation is captured before `f` reads the store, so
+// any write landing after that read is a conflict. The first attempt is
+// followed by up to `max` retries, and the last conflict is returned if none of
+// them commit.
+pub fn retry_on_conflict<K, V, T, F>(map: &mut T, max: usize, mut f: F) -> Result<(), TxError<K>>
+where
+    K: Eq + Hash + Clone,
+    V: Clone,
+    T: HashLike<K, V> + Generation,
+    F: FnMut(&T) -> Changeset<K, V>,
+{
+    let mut attempts = 0;
+    loop {
+        let mut wrap: HashWrap<_, _, _> = HashWrap::new_checked(&mut *map);
+        let cs = f(&*wrap.inner);
+        wrap.apply_changeset(cs);
+        match wrap.try_commit() {
+            Ok(()) => return Ok(()),
+            Err(e) => {
+                if attempts == max {
+                    return Err(e.into());
+                }
+            }
+        }
+        attempts += 1;
+    }
+}
+
+pub(crate) fn apply_staging<K, V, M>(
+    target: &mut M,
+    added: &mut HashMap<K, V>,
+    removed: &HashSet<K>,
+) -> CommitReport
+where
+    K: Eq + Hash,
+    M: HashLike<K, V>,
+{
+    let mut report = CommitReport::default();
+    for k in removed {
+        if target.remove(k).is_some() {
+            report.removed += 1;
+        }
+    }
+    for (k, v) in added.drain() {
+        if target.insert(k, v).is_some() {
+            report.overwritten += 1;
+        } else {
+            report.inserted += 1;
+        }
+    }
+    report
+}
+
 impl<'a, K, V, T, B> HashWrap<'a, K, V, T, B>
 where
     HashWrap<'a, K, V, T, B>: SpecDrop,
@@ -53,282 +307,2675 @@ where
             inner: map,
             added: HashMap::new(),
             removed: HashSet::new(),
+            savepoints: Vec::new(),
+            next_savepoint: 0,
+            undo_log: Vec::new(),
+            redo_log: Vec::new(),
+            undoable: false,
+            baseline: None,
+            generation: None,
+            commit_hook: None,
             commit_behaviour: PhantomData,
             finalised: false,
         }
     }
 
-    fn _commit(&mut self) {
-        for k in &self.removed {
-            self.inner.remove(&k);
-        }
-        for (k, v) in self.added.drain() {
-            self.inner.insert(k, v);
-        }
+    pub fn with_capacity(map: &'a mut T, added_cap: usize, removed_cap: usize) -> Self {
+        let mut wrap = HashWrap::new(map);
+        wrap.added = HashMap::with_capacity(added_cap);
+        wrap.removed = HashSet::with_capacity(removed_cap);
+        wrap
+    }
+
+    // Keeps a copy of the backing map as it was when the wrap was opened, so
+    // that `commit_with_strategy` can tell which staged keys have since been
+    // changed underneath it.
+    pub fn with_baseline(map: &'a mut T) -> Self
+    where
+        T: Clone,
+    {
+        let baseline = map.clone();
+        let mut wrap = HashWrap::new(map);
+        wrap.baseline = Some(baseline);
+        wrap
+    }
+
+    pub fn reserve(&mut self, additional: usize) {
+        self.added.reserve(additional);
+        self.removed.reserve(additional);
+    }
+
+    pub fn from_changeset(map: &'a mut T, added: HashMap<K, V>, removed: HashSet<K>) -> Self {
+        debug_assert!(added.keys().all(|k| !removed.contains(k)));
+        let mut wrap = HashWrap::new(map);
+        wrap.added = added;
+        wrap.removed = removed;
+        wrap
+    }
+
+    pub fn with_commit_hook<F>(mut self, f: F) -> Self
+    where
+        K: Clone,
+        V: Clone,
+        F: FnOnce(&HashMap<K, V>, &HashSet<K>) + 'a,
+    {
+        self.commit_hook = Some(CommitHook {
+            hook: Box::new(f),
+            copy: |added, removed| (added.clone(), removed.clone()),
+        });
+        self
+    }
+
+    // Logging an edit clones how every key it touches was staged, so undo and
+    // redo are only available once asked for here.
+    pub fn with_undo(mut self) -> Self {
+        self.undoable = true;
+        self
+    }
+
+    // Returns the hook along with a copy of the staging it is to be handed
+    // once the commit has landed.
+    fn _begin_commit(&mut self) -> Option<PendingHook<'a, K, V>> {
+        debug_event!(
+            behavior = B::NAME,
+            insertions = self.added.len(),
+            removals = self.removed.len(),
+            "commit"
+        );
+        let CommitHook { hook, copy } = self.commit_hook.take()?;
+        Some((hook, copy(&self.added, &self.removed)))
+    }
+
+    fn _commit(&mut self) -> CommitReport {
+        let hook = self._begin_commit();
+        let report = apply_staging(self.inner, &mut self.added, &self.removed);
         self.finalised = true;
+        if let Some((hook, (added, removed))) = hook {
+            hook(&added, &removed);
+        }
+        report
     }
 
     pub fn commit(mut self) {
+        self._commit();
+    }
+
+    pub fn commit_into<M>(mut self, target: &mut M)
+    where
+        M: HashLike<K, V>,
+    {
+        let hook = self._begin_commit();
+        apply_staging(target, &mut self.added, &self.removed);
+        self.finalised = true;
+        if let Some((hook, (added, removed))) = hook {
+            hook(&added, &removed);
+        }
+    }
+
+    pub fn commit_reported(mut self) -> CommitReport {
         self._commit()
     }
 
+    pub fn commit_if<F>(mut self, pred: F) -> bool
+    where
+        F: FnOnce(&HashMap<K, V>, &HashSet<K>) -> bool,
+    {
+        if pred(&self.added, &self.removed) {
+            self._commit();
+            true
+        } else {
+            self._rollback();
+            false
+        }
+    }
+
+    pub fn commit_with<F>(mut self, mut resolve: F)
+    where
+        F: FnMut(&K, &V, V) -> V,
+    {
+        let inner = &*self.inner;
+        self.added = mem::take(&mut self.added)
+            .into_iter()
+            .map(|(k, v)| {
+                let v = match inner.get(&k) {
+                    Some(current) => resolve(&k, current, v),
+                    None => v,
+                };
+                (k, v)
+            })
+            .collect();
+        self._commit();
+    }
+
+    // Only staged keys whose live value has moved away from the baseline taken
+    // by `with_baseline` are in conflict, and everything else commits as
+    // staged. A wrap opened without a baseline can't tell, so every staged key
+    // that is live in the backing map is treated as changed.
+    pub fn commit_with_strategy(mut self, strategy: MergeStrategy<'_, K, V>)
+    where
+        V: PartialEq,
+    {
+        let (inner, baseline) = (&*self.inner, self.baseline.take());
+        let changed = |k: &K| match &baseline {
+            Some(baseline) => inner.get(k) != baseline.get(k),
+            None => inner.contains_key(k),
+        };
+        match strategy {
+            MergeStrategy::OursWins => {}
+            MergeStrategy::TheirsWins => {
+                self.added.retain(|k, _| !changed(k));
+                self.removed.retain(|k| !changed(k));
+            }
+            MergeStrategy::Custom(resolve) => {
+                self.added = mem::take(&mut self.added)
+                    .into_iter()
+                    .map(|(k, v)| {
+                        let v = match inner.get(&k) {
+                            Some(current) if changed(&k) => resolve(&k, current, v),
+                            _ => v,
+                        };
+                        (k, v)
+                    })
+                    .collect();
+            }
+        }
+        self._commit();
+    }
+
+    // `baseline` is a snapshot of the backing map from when the wrap was
+    // opened. Every staged key whose live value no longer matches it is a
+    // conflict, and any conflict rolls the whole wrap back.
+    pub fn commit_checked(mut self, baseline: &HashMap<K, V>) -> Result<(), Vec<K>>
+    where
+        K: Clone,
+        V: PartialEq,
+    {
+        let inner = &*self.inner;
+        let conflicts: Vec<K> = self
+            .added
+            .keys()
+            .chain(self.removed.iter())
+            .filter(|k| inner.get(k) != baseline.get(k))
+            .cloned()
+            .collect();
+        if conflicts.is_empty() {
+            self._commit();
+            Ok(())
+        } else {
+            self._rollback();
+            Err(conflicts)
+        }
+    }
+
     fn _rollback(&mut self) {
+        debug_event!(
+            behavior = B::NAME,
+            insertions = self.added.len(),
+            removals = self.removed.len(),
+            "rollback"
+        );
         self.finalised = true;
     }
 
+    fn trace_drop(&self) {
+        debug_event!(
+            behavior = B::NAME,
+            insertions = self.added.len(),
+            removals = self.removed.len(),
+            "drop without finalising"
+        );
+    }
+
     pub fn rollback(mut self) {
         self._rollback()
     }
 
+    pub fn discard(self) {
+        self.rollback()
+    }
+
+    pub fn is_finalised(&self) -> bool {
+        self.finalised
+    }
+
+    // The non-panicking counterpart to dropping an unfinalised wrap. Staged
+    // changes are rolled back and reported as `TxError::Unfinalised`; a wrap
+    // with nothing staged closes cleanly.
+    pub fn try_close(mut self) -> Result<(), TxError<K>> {
+        let staged = !self.added.is_empty() || !self.removed.is_empty();
+        self._rollback();
+        if staged {
+            Err(TxError::Unfinalised)
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn behavior_name(&self) -> &'static str {
+        B::NAME
+    }
+
+    pub fn rollback_reported(mut self) -> Changeset<K, V> {
+        self._rollback();
+        Changeset {
+            added: mem::take(&mut self.added),
+            removed: mem::take(&mut self.removed),
+        }
+    }
+
     pub fn contains_key(&self, k: &K) -> bool {
         !self.removed.contains(k) && (self.added.contains_key(k) || self.inner.contains_key(k))
     }
-}
 
-impl<'a, K, V, T> HashWrap<'a, K, V, T>
-where
-    K: Eq + Hash + Clone,
-    T: HashLike<K, V>,
-    V: Clone,
-{
-    pub fn insert(&mut self, k: K, v: V) -> Option<V> {
-        if self.added.contains_key(&k) {
-            self.added.insert(k, v)
-        } else {
-            if self.removed.contains(&k) {
-                self.removed.remove(&k);
-                self.added.insert(k, v)
-            } else {
-                if self.inner.contains_key(&k) {
-                    let ret = Some(self.inner.get(&k).unwrap().clone());
-                    self.added.insert(k, v);
-                    ret
-                } else {
-                    self.added.insert(k, v);
-                    None
-                }
-            }
+    pub fn assert_consistent(&self) {
+        assert!(
+            self.added.keys().all(|k| !self.removed.contains(k)),
+            "Error: Key staged for both insertion and removal."
+        );
+    }
+
+    fn validate_staging(&self) {
+        if cfg!(debug_assertions) {
+            self.assert_consistent();
         }
     }
 
-    pub fn remove(&mut self, k: &K) -> Option<V> {
+    pub fn get(&self, k: &K) -> Option<&V> {
         if self.added.contains_key(k) {
-            self.removed.insert(k.clone());
-            self.added.remove(k)
+            self.added.get(k)
         } else {
             if self.removed.contains(k) {
                 None
             } else {
-                self.removed.insert(k.clone());
-                if self.inner.contains_key(k) {
-                    Some(self.inner.get(k).unwrap().clone())
-                } else {
-                    None
-                }
+                self.inner.get(k)
             }
         }
     }
 
-    pub fn get_mut(&mut self, k: &K) -> Option<&mut V> {
-        if self.added.contains_key(k) {
-            self.added.get_mut(k)
+    pub fn try_get(&self, k: &K) -> Result<&V, KeyError> {
+        if let Some(v) = self.added.get(k) {
+            Ok(v)
         } else {
             if self.removed.contains(k) {
-                None
+                Err(KeyError::Removed)
             } else {
-                if self.inner.contains_key(k) {
-                    self.added
-                        .insert(k.clone(), self.inner.get(k).unwrap().clone());
-                    self.added.get_mut(k)
-                } else {
-                    None
-                }
+                self.inner.get(k).ok_or(KeyError::Absent)
             }
         }
     }
-}
-
-impl<'a, 'b, K, V, T> Index<&'b K> for HashWrap<'a, K, V, T>
-where
-    K: Eq + Hash + Clone,
-    T: HashLike<K, V>,
-    V: Clone,
-{
-    type Output = V;
 
-    fn index(&self, index: &'b K) -> &Self::Output {
-        if self.added.contains_key(index) {
-            self.added.index(index)
+    pub fn get_source(&self, k: &K) -> Option<Source<&V>> {
+        if self.added.contains_key(k) {
+            self.added.get(k).map(Source::Staged)
         } else {
-            if self.removed.contains(index) {
-                panic!()
+            if self.removed.contains(k) {
+                None
             } else {
-                self.inner.get(index).unwrap()
+                self.inner.get(k).map(Source::Original)
             }
         }
     }
-}
 
-#[cfg(feature = "multikey")]
-impl<K, SK, V> HashWrap<'_, K, V, MultiKeyMap<K, SK, V>>
-where
-    K: Eq + Hash + Clone,
-    for<'b> &'b K: IntoIterator<Item = &'b SK>,
-    SK: Eq + Hash + Clone,
-    V: Clone,
-{
-    pub fn keys_containing(&self, k: &SK) -> Option<impl Iterator<Item = &K>> {
-        let i_keys = self.inner.keys_containing(k).map(|i| i.collect::<Vec<_>>());
-        if let Some(mut inner) = i_keys {
-            for k in &self.removed {
-                inner.remove_item(&k);
-            }
-            for (ak, _v) in &self.added {
-                for sk in ak {
-                    if sk == k && !inner.contains(&ak) {
-                        inner.push(ak);
-                    }
-                }
-            }
-            Some(inner.into_iter())
-        } else {
-            None
-        }
+    pub fn revert_key(&mut self, k: &K) {
+        self.added.remove(k);
+        self.removed.remove(k);
     }
-}
 
-pub trait SpecDrop {
-    fn spec_drop(&mut self);
-}
+    pub fn pending_changes(&self) -> (impl Iterator<Item = (&K, &V)>, impl Iterator<Item = &K>) {
+        (self.added.iter(), self.removed.iter())
+    }
 
-impl<'a, K, V, T> SpecDrop for HashWrap<'a, K, V, T, commit_behavior::PanicIfUnfinalised>
-where
-    K: Eq + Hash,
-    T: HashLike<K, V>,
-{
-    fn spec_drop(&mut self) {
-        panic!("Error: Dropping wrapper without calling commit or rollback.")
+    pub fn view(&self) -> HashView<'_, 'a, K, V, T, B> {
+        HashView { wrap: self }
     }
-}
 
-impl<'a, K, V, T> SpecDrop for HashWrap<'a, K, V, T, commit_behavior::ImplicitCommit>
-where
-    K: Eq + Hash,
-    T: HashLike<K, V>,
-{
-    fn spec_drop(&mut self) {
-        self._commit();
+    pub fn dirty_keys(&self) -> impl Iterator<Item = &K> {
+        self.added.keys().chain(self.removed.iter())
     }
-}
 
-impl<'a, K, V, T> SpecDrop for HashWrap<'a, K, V, T, commit_behavior::ImplicitRollback>
-where
-    K: Eq + Hash,
-    T: HashLike<K, V>,
-{
-    fn spec_drop(&mut self) {
-        self._rollback();
+    pub fn removed_keys(&self) -> impl Iterator<Item = &K> {
+        self.removed.iter()
+    }
+
+    pub fn staged_insertions(&self) -> &HashMap<K, V> {
+        &self.added
+    }
+
+    pub fn staged_removals(&self) -> &HashSet<K> {
+        &self.removed
     }
 }
 
-impl<'a, K, V, T, B> Drop for HashWrap<'a, K, V, T, B>
+impl<'a, K, V, T, B> HashWrap<'a, K, V, T, B>
 where
     HashWrap<'a, K, V, T, B>: SpecDrop,
     K: Eq + Hash,
-    T: HashLike<K, V>,
+    T: HashLike<K, V> + Generation,
     B: commit_behavior::Behavior,
 {
-    fn drop(&mut self) {
-        if !self.finalised {
-            self.spec_drop();
+    pub fn new_checked(map: &'a mut T) -> Self {
+        let generation = map.generation();
+        let mut wrap = HashWrap::new(map);
+        wrap.generation = Some(generation);
+        wrap
+    }
+
+    pub fn has_conflict(&self) -> bool {
+        match self.generation {
+            Some(g) => g != self.inner.generation(),
+            None => false,
         }
     }
-}
 
-#[cfg(test)]
-mod test {
+    pub fn try_commit(mut self) -> Result<(), ConflictError<K>> {
+        if self.has_conflict() {
+            let mut keys: Vec<K> = self.added.drain().map(|(k, _)| k).collect();
+            keys.extend(self.removed.drain());
+            self._rollback();
+            Err(ConflictError { keys })
+        } else {
+            self._commit();
+            Ok(())
+        }
+    }
+}
+
+// HashLike has no way to walk its entries, so anything that needs the full view
+// additionally requires the backing map to be iterable by reference.
+impl<'a, K, V, T, B> HashWrap<'a, K, V, T, B>
+where
+    HashWrap<'a, K, V, T, B>: SpecDrop,
+    K: Eq + Hash,
+    T: HashLike<K, V>,
+    for<'b> &'b T: IntoIterator<Item = (&'b K, &'b V)>,
+    B: commit_behavior::Behavior,
+{
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        let (added, removed) = (&self.added, &self.removed);
+        (&*self.inner)
+            .into_iter()
+            .filter(move |(k, _)| !removed.contains(k) && !added.contains_key(k))
+            .chain(added.iter())
+    }
+
+    pub fn iter_ordered(&self) -> impl Iterator<Item = (&K, &V)>
+    where
+        K: Ord,
+    {
+        let (added, removed) = (&self.added, &self.removed);
+        let mut staged: Vec<_> = added.iter().collect();
+        staged.sort_by(|a, b| a.0.cmp(b.0));
+        let mut staged = staged.into_iter().peekable();
+        let mut original = (&*self.inner)
+            .into_iter()
+            .filter(move |(k, _)| !removed.contains(k) && !added.contains_key(k))
+            .peekable();
+        iter::from_fn(move || match (original.peek(), staged.peek()) {
+            (Some(o), Some(s)) if o.0 < s.0 => original.next(),
+            (Some(_), None) => original.next(),
+            _ => staged.next(),
+        })
+    }
+
+    pub fn debug_view(&self) -> HashView<'_, 'a, K, V, T, B>
+    where
+        K: fmt::Debug,
+        V: fmt::Debug,
+    {
+        self.view()
+    }
+
+    pub fn eq_view(&self, other: &HashMap<K, V>) -> bool
+    where
+        V: PartialEq,
+    {
+        self.iter().count() == other.len() && self.iter().all(|(k, v)| other.get(k) == Some(v))
+    }
+
+    pub fn contains_value(&self, v: &V) -> bool
+    where
+        V: PartialEq,
+    {
+        self.iter().any(|(_, x)| x == v)
+    }
+}
+
+impl<'a, K, V, T> HashWrap<'a, K, V, T>
+where
+    K: Eq + Hash + Clone,
+    T: HashLike<K, V>,
+    V: Clone,
+{
+    pub fn insert(&mut self, k: K, v: V) -> Option<V> {
+        self.record(iter::once(&k));
+        let ret = if self.added.contains_key(&k) {
+            self.added.insert(k, v)
+        } else {
+            if self.removed.contains(&k) {
+                self.removed.remove(&k);
+                self.added.insert(k, v)
+            } else {
+                let ret = self.inner.get(&k).cloned();
+                self.added.insert(k, v);
+                ret
+            }
+        };
+        self.validate_staging();
+        ret
+    }
+
+    pub fn replace(&mut self, k: K, v: V) -> Option<V> {
+        self.insert(k, v)
+    }
+
+    pub fn remove(&mut self, k: &K) -> Option<V> {
+        self.record(iter::once(k));
+        let ret = if self.added.contains_key(k) {
+            self.removed.insert(k.clone());
+            self.added.remove(k)
+        } else {
+            if self.removed.contains(k) {
+                None
+            } else {
+                self.removed.insert(k.clone());
+                self.inner.get(k).cloned()
+            }
+        };
+        self.validate_staging();
+        ret
+    }
+
+    pub fn remove_entry(&mut self, k: &K) -> Option<(K, V)> {
+        self.record(iter::once(k));
+        if self.added.contains_key(k) {
+            self.removed.insert(k.clone());
+            self.added.remove_entry(k)
+        } else {
+            if self.removed.contains(k) {
+                None
+            } else {
+                self.removed.insert(k.clone());
+                self.inner.get(k).map(|v| (k.clone(), v.clone()))
+            }
+        }
+    }
+
+    pub fn remove_many(&mut self, ks: &[K]) -> usize {
+        self.record(ks);
+        self.removed.reserve(ks.len());
+        let mut count = 0;
+        for k in ks {
+            if self.contains_key(k) {
+                self.added.remove(k);
+                self.removed.insert(k.clone());
+                count += 1;
+            }
+        }
+        self.validate_staging();
+        count
+    }
+
+    // The edit made through the returned reference isn't seen here, so what is
+    // recorded for undo is the key's staging just before it was handed out.
+    pub fn get_mut(&mut self, k: &K) -> Option<&mut V> {
+        if self.added.contains_key(k) {
+            self.record(iter::once(k));
+        } else {
+            if self.removed.contains(k) {
+                return None;
+            }
+            let v = self.inner.get(k)?.clone();
+            self.push_undo(vec![(k.clone(), Staged::Clean)]);
+            self.added.insert(k.clone(), v);
+            self.validate_staging();
+        }
+        self.added.get_mut(k)
+    }
+
+    fn stage_original(&mut self, k: &K) {
+        if !self.added.contains_key(k) && !self.removed.contains(k) {
+            if let Some(v) = self.inner.get(k) {
+                self.added.insert(k.clone(), v.clone());
+            }
+        }
+        self.validate_staging();
+    }
+
+    pub fn try_insert_all<I, E, F>(&mut self, items: I, validate: F) -> Result<(), E>
+    where
+        I: IntoIterator<Item = (K, V)>,
+        F: Fn(&K, &V) -> Result<(), E>,
+    {
+        let items: Vec<(K, V)> = items.into_iter().collect();
+        for (k, v) in &items {
+            validate(k, v)?;
+        }
+        self.extend(items);
+        Ok(())
+    }
+
+    pub fn get_many_mut<const N: usize>(&mut self, ks: [&K; N]) -> Option<[&mut V; N]> {
+        for (i, k) in ks.iter().enumerate() {
+            if ks[..i].contains(k) || !self.contains_key(k) {
+                return None;
+            }
+        }
+        self.record(ks.iter().copied());
+        for k in &ks {
+            self.stage_original(k);
+        }
+        let added = &mut self.added;
+        Some(ks.map(|k| {
+            let v: *mut V = added.get_mut(k).unwrap();
+            // The keys were checked to be distinct above, so each of these
+            // borrows is of a different value in `added`.
+            unsafe { &mut *v }
+        }))
+    }
+
+    pub fn swap(&mut self, a: &K, b: &K) -> bool {
+        if a == b {
+            return self.contains_key(a);
+        }
+        match self.get_many_mut([a, b]) {
+            Some([x, y]) => {
+                mem::swap(x, y);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn get_or_insert_with<F: FnOnce() -> V>(&mut self, k: K, f: F) -> &mut V {
+        self.record(iter::once(&k));
+        let (inner, removed) = (&*self.inner, &mut self.removed);
+        match self.added.entry(k) {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => {
+                let v = if removed.remove(e.key()) {
+                    f()
+                } else {
+                    inner.get(e.key()).cloned().unwrap_or_else(f)
+                };
+                e.insert(v)
+            }
+        }
+    }
+
+    pub fn preview(&self) -> T
+    where
+        T: Clone,
+    {
+        let mut map = (*self.inner).clone();
+        for k in &self.removed {
+            map.remove(k);
+        }
+        for (k, v) in &self.added {
+            map.insert(k.clone(), v.clone());
+        }
+        map
+    }
+
+    pub fn snapshot(&self) -> Changeset<K, V> {
+        Changeset {
+            added: self.added.clone(),
+            removed: self.removed.clone(),
+        }
+    }
+
+    pub fn get_or_insert(&mut self, k: K, default: V) -> &mut V {
+        self.get_or_insert_with(k, || default)
+    }
+
+    pub fn merge(&mut self, other: Changeset<K, V>) {
+        for k in &other.removed {
+            self.remove(k);
+        }
+        for (k, v) in other.added {
+            self.insert(k, v);
+        }
+    }
+
+    pub fn apply_changeset(&mut self, mut cs: Changeset<K, V>) {
+        let removed = &cs.removed;
+        cs.added.retain(|k, _| !removed.contains(k));
+        self.merge(cs);
+    }
+
+    fn push_savepoint(&mut self, label: Option<String>) -> SavepointId {
+        let id = SavepointId(self.next_savepoint);
+        self.next_savepoint += 1;
+        self.savepoints
+            .push((id, label, self.added.clone(), self.removed.clone()));
+        id
+    }
+
+    pub fn savepoint(&mut self) -> SavepointId {
+        self.push_savepoint(None)
+    }
+
+    // Drops every savepoint taken after the one at `pos`, labelled or not, and
+    // restores the staging it holds.
+    fn restore_savepoint(&mut self, pos: usize) {
+        self.savepoints.truncate(pos + 1);
+        let (_, _, added, removed) = &self.savepoints[pos];
+        self.added = added.clone();
+        self.removed = removed.clone();
+        self.clear_history();
+    }
+
+    pub fn rollback_to(&mut self, id: SavepointId) -> Result<(), SavepointError> {
+        let pos = self
+            .savepoints
+            .iter()
+            .rposition(|(sp, _, _, _)| *sp == id)
+            .ok_or(SavepointError)?;
+        self.restore_savepoint(pos);
+        Ok(())
+    }
+
+    // The logs describe edits relative to the staging they were made on, so
+    // they no longer apply once the staging has been rolled back past them.
+    fn clear_history(&mut self) {
+        self.undo_log.clear();
+        self.redo_log.clear();
+    }
+
+    fn staged(&self, k: &K) -> Staged<V> {
+        match self.added.get(k) {
+            Some(v) => Staged::Added(v.clone()),
+            None => {
+                if self.removed.contains(k) {
+                    Staged::Removed
+                } else {
+                    Staged::Clean
+                }
+            }
+        }
+    }
+
+    fn restage(&mut self, k: K, staged: Staged<V>) {
+        self.added.remove(&k);
+        self.removed.remove(&k);
+        match staged {
+            Staged::Added(v) => {
+                self.added.insert(k, v);
+            }
+            Staged::Removed => {
+                self.removed.insert(k);
+            }
+            Staged::Clean => {}
+        }
+        self.validate_staging();
+    }
+
+    fn record<'k, I>(&mut self, ks: I)
+    where
+        I: IntoIterator<Item = &'k K>,
+        K: 'k,
+    {
+        if self.undoable {
+            let edit = ks
+                .into_iter()
+                .map(|k| (k.clone(), self.staged(k)))
+                .collect();
+            self.push_undo(edit);
+        }
+    }
+
+    fn push_undo(&mut self, edit: Edit<K, V>) {
+        if self.undoable {
+            self.undo_log.push(edit);
+            self.redo_log.clear();
+        }
+    }
+
+    // Puts back every key of `edit`, returning how they were staged before.
+    fn revert(&mut self, edit: Edit<K, V>) -> Edit<K, V> {
+        let current = edit
+            .iter()
+            .map(|(k, _)| (k.clone(), self.staged(k)))
+            .collect();
+        for (k, staged) in edit {
+            self.restage(k, staged);
+        }
+        current
+    }
+
+    pub fn undo(&mut self) -> bool {
+        match self.undo_log.pop() {
+            Some(edit) => {
+                let redo = self.revert(edit);
+                self.redo_log.push(redo);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn redo(&mut self) -> bool {
+        match self.redo_log.pop() {
+            Some(edit) => {
+                let undo = self.revert(edit);
+                self.undo_log.push(undo);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // A checkpoint is a savepoint found by its label rather than its id.
+    pub fn checkpoint(&mut self, label: impl Into<String>) {
+        self.push_savepoint(Some(label.into()));
+    }
+
+    pub fn rollback_to_label(&mut self, label: &str) -> bool {
+        match self
+            .savepoints
+            .iter()
+            .rposition(|(_, l, _, _)| l.as_deref() == Some(label))
+        {
+            Some(pos) => {
+                self.restore_savepoint(pos);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl<'a, K, V, T> HashWrap<'a, K, V, T>
+where
+    K: Eq + Hash + Clone,
+    T: HashLike<K, V>,
+    for<'b> &'b T: IntoIterator<Item = (&'b K, &'b V)>,
+    V: Clone,
+{
+    fn record_all(&mut self) {
+        if self.undoable {
+            let keys: Vec<K> = self.iter().map(|(k, _)| k.clone()).collect();
+            self.record(&keys);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.record_all();
+        self.added.clear();
+        self.removed
+            .extend((&*self.inner).into_iter().map(|(k, _)| k.clone()));
+    }
+
+    pub fn retain<F>(&mut self, mut f: F)
+    where
+        F: FnMut(&K, &V) -> bool,
+    {
+        self.record_all();
+        let inner = &*self.inner;
+        let removed = &mut self.removed;
+        self.added.retain(|k, v| {
+            let keep = f(k, v);
+            if !keep && inner.contains_key(k) {
+                removed.insert(k.clone());
+            }
+            keep
+        });
+        for (k, v) in inner {
+            if !self.added.contains_key(k) && !removed.contains(k) && !f(k, v) {
+                removed.insert(k.clone());
+            }
+        }
+    }
+
+    pub fn diff(&self, other: &HashMap<K, V>) -> MapDiff<K, V>
+    where
+        V: PartialEq,
+    {
+        let mut diff = MapDiff {
+            only_in_view: HashMap::new(),
+            only_in_other: HashMap::new(),
+            differing: HashMap::new(),
+        };
+        for (k, v) in self.iter() {
+            match other.get(k) {
+                None => {
+                    diff.only_in_view.insert(k.clone(), v.clone());
+                }
+                Some(o) if o != v => {
+                    diff.differing.insert(k.clone(), (v.clone(), o.clone()));
+                }
+                Some(_) => {}
+            }
+        }
+        for (k, v) in other {
+            if !self.contains_key(k) {
+                diff.only_in_other.insert(k.clone(), v.clone());
+            }
+        }
+        diff
+    }
+
+    // Every untouched value is cloned out of the backing map into the staging,
+    // so this costs a copy of the whole map.
+    pub fn map_values<F>(&mut self, mut f: F)
+    where
+        F: FnMut(V) -> V,
+    {
+        self.record_all();
+        let (inner, added, removed) = (&*self.inner, &mut self.added, &self.removed);
+        for (k, v) in inner {
+            if !removed.contains(k) && !added.contains_key(k) {
+                added.insert(k.clone(), v.clone());
+            }
+        }
+        *added = mem::take(added)
+            .into_iter()
+            .map(|(k, v)| (k, f(v)))
+            .collect();
+    }
+
+    pub fn drain(&mut self) -> impl Iterator<Item = (K, V)> + '_ {
+        self.record_all();
+        let inner = &*self.inner;
+        let (added, removed) = (&mut self.added, &mut self.removed);
+        let mut untouched = Vec::new();
+        for (k, v) in inner {
+            if removed.insert(k.clone()) && !added.contains_key(k) {
+                untouched.push((k, v));
+            }
+        }
+        added
+            .drain()
+            .chain(untouched.into_iter().map(|(k, v)| (k.clone(), v.clone())))
+    }
+}
+
+impl<'a, 'b, K, V, T> Index<&'b K> for HashWrap<'a, K, V, T>
+where
+    K: Eq + Hash + Clone,
+    T: HashLike<K, V>,
+    V: Clone,
+{
+    type Output = V;
+
+    fn index(&self, index: &'b K) -> &Self::Output {
+        if self.added.contains_key(index) {
+            self.added.index(index)
+        } else {
+            if self.removed.contains(index) {
+                panic!()
+            } else {
+                self.inner.get(index).unwrap()
+            }
+        }
+    }
+}
+
+impl<'a, 'b, K, V, T> IndexMut<&'b K> for HashWrap<'a, K, V, T>
+where
+    K: Eq + Hash + Clone,
+    T: HashLike<K, V>,
+    V: Clone,
+{
+    fn index_mut(&mut self, index: &'b K) -> &mut Self::Output {
+        self.get_mut(index).unwrap()
+    }
+}
+
+impl<'a, K, V, T> Extend<(K, V)> for HashWrap<'a, K, V, T>
+where
+    K: Eq + Hash + Clone,
+    T: HashLike<K, V>,
+    V: Clone,
+{
+    fn extend<I: IntoIterator<Item = (K, V)>>(&mut self, iter: I) {
+        for (k, v) in iter {
+            self.insert(k, v);
+        }
+    }
+}
+
+impl<'a, K, V, T> HashLike<K, V> for HashWrap<'a, K, V, T>
+where
+    K: Eq + Hash + Clone,
+    T: HashLike<K, V>,
+    V: Clone,
+{
+    fn get(&self, k: &K) -> Option<&V> {
+        HashWrap::get(self, k)
+    }
+
+    fn insert(&mut self, k: K, v: V) -> Option<V> {
+        HashWrap::insert(self, k, v)
+    }
+
+    fn remove(&mut self, k: &K) -> Option<V> {
+        HashWrap::remove(self, k)
+    }
+
+    fn contains_key(&self, k: &K) -> bool {
+        HashWrap::contains_key(self, k)
+    }
+}
+
+// HashLike only looks up by &K and has no length, so borrowed-key queries and
+// the effective length need a HashMap backing.
+impl<'a, K, V, B> HashWrap<'a, K, V, HashMap<K, V>, B>
+where
+    HashWrap<'a, K, V, HashMap<K, V>, B>: SpecDrop,
+    K: Eq + Hash,
+    B: commit_behavior::Behavior,
+{
+    // Only the staged keys are looked at, so this doesn't walk the backing map.
+    pub fn len(&self) -> usize {
+        let removed = self
+            .removed
+            .iter()
+            .filter(|k| self.inner.contains_key(k))
+            .count();
+        let added = self
+            .added
+            .keys()
+            .filter(|k| !self.inner.contains_key(k))
+            .count();
+        self.inner.len() - removed + added
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn commit_and_len(mut self) -> usize {
+        let len = self.len();
+        self._commit();
+        len
+    }
+
+    pub fn get_borrowed<Q>(&self, k: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        if self.added.contains_key(k) {
+            self.added.get(k)
+        } else {
+            if self.removed.contains(k) {
+                None
+            } else {
+                self.inner.get(k)
+            }
+        }
+    }
+
+    pub fn contains_key_borrowed<Q>(&self, k: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        !self.removed.contains(k) && (self.added.contains_key(k) || self.inner.contains_key(k))
+    }
+}
+
+#[cfg(feature = "multikey")]
+impl<K, SK, V> HashWrap<'_, K, V, MultiKeyMap<K, SK, V>>
+where
+    K: Eq + Hash + Clone,
+    for<'b> &'b K: IntoIterator<Item = &'b SK>,
+    SK: Eq + Hash + Clone,
+    V: Clone,
+{
+    pub fn keys_containing(&self, k: &SK) -> Option<impl Iterator<Item = &K>> {
+        let i_keys = self.inner.keys_containing(k).map(|i| i.collect::<Vec<_>>());
+        if let Some(mut inner) = i_keys {
+            for k in &self.removed {
+                inner.retain(|ik| ik != &k);
+            }
+            for (ak, _v) in &self.added {
+                for sk in ak {
+                    if sk == k && !inner.contains(&ak) {
+                        inner.push(ak);
+                    }
+                }
+            }
+            Some(inner.into_iter())
+        } else {
+            None
+        }
+    }
+}
+
+/// A read-only view of a wrap's effective state.
+///
+/// ```compile_fail
+/// use std::collections::HashMap;
+/// use transactions::hash_wrap::HashWrap;
+///
+/// let mut map: HashMap<i32, i32> = HashMap::new();
+/// let wrap: HashWrap<_, _> = HashWrap::new(&mut map);
+/// wrap.view().insert(1, 1);
+/// ```
+pub struct HashView<'w, 'a, K, V, T = HashMap<K, V>, B = commit_behavior::PanicIfUnfinalised>
+where
+    HashWrap<'a, K, V, T, B>: SpecDrop,
+    K: Eq + Hash,
+    T: HashLike<K, V>,
+    B: commit_behavior::Behavior,
+{
+    wrap: &'w HashWrap<'a, K, V, T, B>,
+}
+
+impl<'w, 'a, K, V, T, B> HashView<'w, 'a, K, V, T, B>
+where
+    HashWrap<'a, K, V, T, B>: SpecDrop,
+    K: Eq + Hash,
+    T: HashLike<K, V>,
+    B: commit_behavior::Behavior,
+{
+    pub fn contains_key(&self, k: &K) -> bool {
+        self.wrap.contains_key(k)
+    }
+
+    pub fn get(&self, k: &K) -> Option<&'w V> {
+        self.wrap.get(k)
+    }
+}
+
+impl<'w, 'a, K, V, T, B> HashView<'w, 'a, K, V, T, B>
+where
+    HashWrap<'a, K, V, T, B>: SpecDrop,
+    K: Eq + Hash,
+    T: HashLike<K, V>,
+    for<'b> &'b T: IntoIterator<Item = (&'b K, &'b V)>,
+    B: commit_behavior::Behavior,
+{
+    pub fn iter(&self) -> impl Iterator<Item = (&'w K, &'w V)> {
+        self.wrap.iter()
+    }
+}
+
+impl<'w, 'a, K, V, B> HashView<'w, 'a, K, V, HashMap<K, V>, B>
+where
+    HashWrap<'a, K, V, HashMap<K, V>, B>: SpecDrop,
+    K: Eq + Hash,
+    B: commit_behavior::Behavior,
+{
+    pub fn len(&self) -> usize {
+        self.wrap.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.wrap.is_empty()
+    }
+}
+
+impl<'w, 'a, K, V, T, B> fmt::Debug for HashView<'w, 'a, K, V, T, B>
+where
+    HashWrap<'a, K, V, T, B>: SpecDrop,
+    K: Eq + Hash + fmt::Debug,
+    V: fmt::Debug,
+    T: HashLike<K, V>,
+    for<'b> &'b T: IntoIterator<Item = (&'b K, &'b V)>,
+    B: commit_behavior::Behavior,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()?;
+        write!(
+            f,
+            " ({} staged insertions, {} staged removals)",
+            self.wrap.added.len(),
+            self.wrap.removed.len()
+        )
+    }
+}
+
+impl<'a, K, V, T, B> Transaction for HashWrap<'a, K, V, T, B>
+where
+    HashWrap<'a, K, V, T, B>: SpecDrop,
+    K: Eq + Hash,
+    T: HashLike<K, V>,
+    B: commit_behavior::Behavior,
+{
+    fn commit(self) {
+        HashWrap::commit(self)
+    }
+
+    fn rollback(self) {
+        HashWrap::rollback(self)
+    }
+}
+
+impl<'a, K, V, T, B> TwoPhase for HashWrap<'a, K, V, T, B>
+where
+    HashWrap<'a, K, V, T, B>: SpecDrop,
+    K: Eq + Hash,
+    T: HashLike<K, V> + Generation,
+    B: commit_behavior::Behavior,
+{
+    fn prepare(&mut self) -> bool {
+        !self.has_conflict()
+    }
+
+    fn apply(self: Box<Self>) {
+        HashWrap::commit(*self)
+    }
+
+    fn abort(self: Box<Self>) {
+        HashWrap::rollback(*self)
+    }
+}
+
+pub trait SpecDrop {
+    fn spec_drop(&mut self);
+}
+
+impl_into_behavior! {
+    HashWrap<'a, K, V, T> {
+        take: added, removed, savepoints, undo_log, redo_log, baseline, commit_hook;
+        copy: next_savepoint, undoable, generation;
+    }
+    where { K: Eq + Hash, T: HashLike<K, V> }
+    into_implicit_commit => ImplicitCommit;
+    into_implicit_rollback => ImplicitRollback;
+    into_panicking => PanicIfUnfinalised;
+}
+
+impl_spec_drop! {
+    on_drop => trace_drop;
+    HashWrap<'a, K, V, T> where { K: Eq + Hash, T: HashLike<K, V> }
+    ImplicitCommit => _commit;
+    ImplicitRollback => _rollback;
+}
+
+#[cfg(test)]
+mod test {
+    use std::{cell::Cell, rc::Rc};
+
     use super::*;
 
-    fn get_hash() -> HashMap<i32, String> {
-        let mut h = HashMap::new();
-        h.insert(0, "Zero".to_string());
-        h.insert(1, "One".to_string());
-        h.insert(2, "Two".to_string());
-        h
+    fn get_hash() -> HashMap<i32, String> {
+        let mut h = HashMap::new();
+        h.insert(0, "Zero".to_string());
+        h.insert(1, "One".to_string());
+        h.insert(2, "Two".to_string());
+        h
+    }
+
+    fn check_hash(val: HashMap<i32, String>) {
+        let r = get_hash();
+        assert_eq!(val.len(), r.len());
+        for (k, v) in val {
+            assert_eq!(v, r[&k]);
+        }
+    }
+
+    #[test]
+    fn basic_shadowing() {
+        let mut map = get_hash();
+        let wrap = HashWrap::new(&mut map);
+        assert_eq!(wrap[&1], "One");
+        wrap.commit();
+        check_hash(map);
+    }
+
+    #[test]
+    fn basic_rollback() {
+        let mut map = get_hash();
+        let mut wrap = HashWrap::new(&mut map);
+        assert_eq!(wrap[&2], "Two");
+        let ret = wrap.insert(5, "Five".to_string());
+        assert!(ret.is_none());
+        assert_eq!(wrap[&5], "Five");
+        let ret = wrap.remove(&0);
+        assert!(ret.is_some());
+        assert_eq!(ret.unwrap(), "Zero");
+        wrap.rollback();
+        check_hash(map);
+    }
+
+    #[test]
+    fn basic_edits() {
+        let mut map = get_hash();
+        let mut wrap = HashWrap::new(&mut map);
+        let ret = wrap.insert(5, "Five".to_string());
+        assert!(ret.is_none());
+        wrap.get_mut(&2).unwrap().push_str("00");
+        wrap.commit();
+        assert!(map.contains_key(&5));
+        assert_eq!(map[&2], "Two00");
+    }
+
+    #[test]
+    fn basic_removal() {
+        let mut map = get_hash();
+        let mut wrap = HashWrap::new(&mut map);
+        let ret = wrap.remove(&1);
+        assert!(ret.is_some());
+        assert_eq!(ret.unwrap(), "One");
+        wrap.commit();
+        assert!(!map.contains_key(&1));
+    }
+
+    #[test]
+    fn repeated_insertion() {
+        let mut map = get_hash();
+        let mut wrap = HashWrap::new(&mut map);
+        let ret = wrap.insert(1, "Five".to_string());
+        assert!(ret.is_some());
+        assert_eq!(ret.unwrap(), "One");
+        let ret = wrap.insert(1, "Three".to_string());
+        assert!(ret.is_some());
+        assert_eq!(ret.unwrap(), "Five");
+        let ret = wrap.insert(1, "Four".to_string());
+        assert!(ret.is_some());
+        assert_eq!(ret.unwrap(), "Three");
+        let ret = wrap.remove(&1);
+        assert!(ret.is_some());
+        assert_eq!(ret.unwrap(), "Four");
+        wrap.rollback();
+        check_hash(map);
+    }
+
+    #[test]
+    fn effective_len() {
+        let mut map = get_hash();
+        let mut wrap = HashWrap::new(&mut map);
+        assert_eq!(wrap.len(), 3);
+        wrap.insert(5, "Five".to_string());
+        wrap.insert(1, "Uno".to_string());
+        wrap.remove(&0);
+        wrap.remove(&7);
+        let len = wrap.len();
+        assert_eq!(len, 3);
+        assert!(!wrap.is_empty());
+        wrap.commit();
+        assert_eq!(map.len(), len);
+    }
+
+    #[derive(Debug, PartialEq)]
+    struct NoClone(i32);
+
+    #[test]
+    fn immutable_get() {
+        let mut map = HashMap::new();
+        map.insert(0, NoClone(0));
+        map.insert(1, NoClone(1));
+        let wrap: HashWrap<_, _> = HashWrap::new(&mut map);
+        assert_eq!(wrap.get(&1), Some(&NoClone(1)));
+        assert!(wrap.get(&2).is_none());
+        wrap.rollback();
+
+        let mut map = get_hash();
+        let mut wrap = HashWrap::new(&mut map);
+        wrap.insert(1, "Uno".to_string());
+        wrap.remove(&2);
+        assert_eq!(wrap.get(&0).unwrap(), "Zero");
+        assert_eq!(wrap.get(&1).unwrap(), "Uno");
+        assert!(wrap.get(&2).is_none());
+        wrap.rollback();
+        check_hash(map);
+    }
+
+    #[test]
+    fn effective_iter() {
+        let mut map = get_hash();
+        let mut wrap = HashWrap::new(&mut map);
+        wrap.insert(5, "Five".to_string());
+        wrap.insert(1, "Uno".to_string());
+        wrap.remove(&0);
+        let view: HashMap<i32, String> = wrap.iter().map(|(k, v)| (*k, v.clone())).collect();
+        assert_eq!(wrap.iter().count(), view.len());
+        wrap.commit();
+        assert_eq!(view, map);
+    }
+
+    #[test]
+    fn bulk_extend() {
+        let mut map = get_hash();
+        let mut wrap = HashWrap::new(&mut map);
+        wrap.remove(&2);
+        wrap.extend(vec![
+            (2, "Deux".to_string()),
+            (3, "Three".to_string()),
+            (4, "Four".to_string()),
+        ]);
+        wrap.commit();
+        assert_eq!(map.len(), 5);
+        assert_eq!(map[&2], "Deux");
+        assert_eq!(map[&3], "Three");
+        assert_eq!(map[&4], "Four");
+    }
+
+    #[test]
+    fn clear_rollback() {
+        let mut map = get_hash();
+        let mut wrap = HashWrap::new(&mut map);
+        wrap.insert(5, "Five".to_string());
+        wrap.clear();
+        assert!(wrap.is_empty());
+        assert!(!wrap.contains_key(&0));
+        assert!(!wrap.contains_key(&5));
+        wrap.rollback();
+        check_hash(map);
+
+        let mut map = get_hash();
+        let mut wrap = HashWrap::new(&mut map);
+        wrap.clear();
+        wrap.commit();
+        assert!(map.is_empty());
+    }
+
+    #[test]
+    fn retain_even() {
+        let mut map = get_hash();
+        let mut wrap = HashWrap::new(&mut map);
+        wrap.insert(3, "Three".to_string());
+        wrap.insert(4, "Four".to_string());
+        wrap.insert(1, "Uno".to_string());
+        wrap.retain(|k, _| k % 2 == 0);
+        assert_eq!(wrap.len(), 3);
+        wrap.commit();
+        let mut keys: Vec<_> = map.keys().cloned().collect();
+        keys.sort();
+        assert_eq!(keys, vec![0, 2, 4]);
+    }
+
+    #[test]
+    fn drain_all() {
+        let mut map = get_hash();
+        let mut wrap = HashWrap::new(&mut map);
+        wrap.insert(1, "Uno".to_string());
+        wrap.insert(5, "Five".to_string());
+        wrap.remove(&0);
+        let mut drained: Vec<_> = wrap.drain().collect();
+        drained.sort();
+        assert_eq!(
+            drained,
+            vec![
+                (1, "Uno".to_string()),
+                (2, "Two".to_string()),
+                (5, "Five".to_string()),
+            ]
+        );
+        assert!(wrap.is_empty());
+        wrap.commit();
+        assert!(map.is_empty());
+    }
+
+    #[test]
+    fn inspect_pending() {
+        let mut map = get_hash();
+        let mut wrap = HashWrap::new(&mut map);
+        wrap.insert(5, "Five".to_string());
+        wrap.remove(&0);
+        {
+            let (added, removed) = wrap.pending_changes();
+            let added: Vec<_> = added.collect();
+            let removed: Vec<_> = removed.collect();
+            assert_eq!(added, vec![(&5, &"Five".to_string())]);
+            assert_eq!(removed, vec![&0]);
+        }
+        wrap.rollback();
+        check_hash(map);
+    }
+
+    #[test]
+    fn commit_counts() {
+        let mut map = get_hash();
+        let mut wrap = HashWrap::new(&mut map);
+        wrap.insert(5, "Five".to_string());
+        wrap.insert(6, "Six".to_string());
+        wrap.insert(1, "Uno".to_string());
+        wrap.remove(&0);
+        wrap.remove(&9);
+        let report = wrap.commit_reported();
+        assert_eq!(
+            report,
+            CommitReport {
+                inserted: 2,
+                overwritten: 1,
+                removed: 1,
+            }
+        );
+        assert_eq!(map.len(), 4);
+    }
+
+    #[test]
+    fn nested_wraps() {
+        let mut map = get_hash();
+        let mut outer = HashWrap::new(&mut map);
+        outer.insert(5, "Five".to_string());
+        {
+            let mut inner = HashWrap::new(&mut outer);
+            inner.insert(6, "Six".to_string());
+            inner.remove(&5);
+            inner.remove(&0);
+            inner.commit();
+        }
+        assert!(outer.contains_key(&6));
+        assert!(!outer.contains_key(&5));
+        assert!(!outer.contains_key(&0));
+        outer.rollback();
+        check_hash(map);
+    }
+
+    #[test]
+    fn savepoint_rollback() {
+        let mut map = get_hash();
+        let mut wrap = HashWrap::new(&mut map);
+        wrap.insert(5, "Five".to_string());
+        let first = wrap.savepoint();
+        wrap.insert(6, "Six".to_string());
+        wrap.remove(&0);
+        let second = wrap.savepoint();
+        wrap.insert(7, "Seven".to_string());
+        assert_eq!(wrap.rollback_to(first), Ok(()));
+        assert!(!wrap.contains_key(&6));
+        assert!(wrap.contains_key(&0));
+        assert_eq!(wrap.rollback_to(second), Err(SavepointError));
+        assert!(wrap.contains_key(&5));
+        wrap.commit();
+        assert_eq!(map.len(), 4);
+        assert_eq!(map[&5], "Five");
+        assert!(!map.contains_key(&6));
+        assert!(!map.contains_key(&7));
+    }
+
+    #[test]
+    fn labelled_checkpoints() {
+        let mut map = get_hash();
+        let mut wrap = HashWrap::new(&mut map);
+        wrap.insert(5, "Five".to_string());
+        wrap.checkpoint("before-six");
+        wrap.insert(6, "Six".to_string());
+        wrap.remove(&0);
+        wrap.checkpoint("after-six");
+        wrap.insert(7, "Seven".to_string());
+        assert!(!wrap.rollback_to_label("missing"));
+        assert!(wrap.rollback_to_label("before-six"));
+        assert!(!wrap.contains_key(&6));
+        assert!(wrap.contains_key(&0));
+        assert!(!wrap.rollback_to_label("after-six"));
+        wrap.commit();
+        assert_eq!(map.len(), 4);
+        assert_eq!(map[&5], "Five");
+        assert!(!map.contains_key(&6));
+        assert!(!map.contains_key(&7));
+    }
+
+    #[test]
+    fn checkpoints_share_the_savepoint_stack() {
+        let mut map = get_hash();
+        let mut wrap = HashWrap::new(&mut map);
+        let first = wrap.savepoint();
+        wrap.insert(5, "Five".to_string());
+        wrap.checkpoint("five");
+        wrap.insert(6, "Six".to_string());
+        assert_eq!(wrap.rollback_to(first), Ok(()));
+        assert!(!wrap.rollback_to_label("five"));
+        assert!(!wrap.contains_key(&5));
+
+        wrap.checkpoint("empty");
+        wrap.insert(7, "Seven".to_string());
+        let second = wrap.savepoint();
+        wrap.insert(8, "Eight".to_string());
+        assert!(wrap.rollback_to_label("empty"));
+        assert_eq!(wrap.rollback_to(second), Err(SavepointError));
+        assert_eq!(wrap.rollback_to(first), Ok(()));
+        wrap.commit();
+        assert_eq!(map, get_hash());
+    }
+
+    #[test]
+    fn lazy_get_or_insert() {
+        let mut map = get_hash();
+        let mut wrap = HashWrap::new(&mut map);
+        wrap.get_or_insert_with(1, || panic!()).push_str("00");
+        wrap.get_or_insert_with(5, || "Five".to_string());
+        wrap.remove(&2);
+        wrap.get_or_insert_with(2, || "Deux".to_string());
+        wrap.commit();
+        assert_eq!(map[&1], "One00");
+        assert_eq!(map[&2], "Deux");
+        assert_eq!(map[&5], "Five");
+    }
+
+    #[test]
+    fn merge_changesets() {
+        let mut map = get_hash();
+        let mut wrap = HashWrap::new(&mut map);
+        let mut first = Changeset {
+            added: HashMap::new(),
+            removed: HashSet::new(),
+        };
+        first.added.insert(5, "Five".to_string());
+        first.removed.insert(0);
+        let mut second = Changeset {
+            added: HashMap::new(),
+            removed: HashSet::new(),
+        };
+        second.added.insert(1, "Uno".to_string());
+        second.removed.insert(2);
+        wrap.merge(first);
+        wrap.merge(second);
+        wrap.commit();
+        assert_eq!(map.len(), 2);
+        assert_eq!(map[&1], "Uno");
+        assert_eq!(map[&5], "Five");
+    }
+
+    struct Versioned {
+        map: HashMap<i32, String>,
+        generation: u64,
+    }
+
+    impl HashLike<i32, String> for Versioned {
+        fn get(&self, k: &i32) -> Option<&String> {
+            self.map.get(k)
+        }
+
+        fn insert(&mut self, k: i32, v: String) -> Option<String> {
+            self.generation += 1;
+            self.map.insert(k, v)
+        }
+
+        fn remove(&mut self, k: &i32) -> Option<String> {
+            self.generation += 1;
+            self.map.remove(k)
+        }
+
+        fn contains_key(&self, k: &i32) -> bool {
+            self.map.contains_key(k)
+        }
+    }
+
+    impl Generation for Versioned {
+        fn generation(&self) -> u64 {
+            self.generation
+        }
+    }
+
+    #[test]
+    fn conflict_detection() {
+        let mut store = Versioned {
+            map: get_hash(),
+            generation: 0,
+        };
+        let mut wrap: HashWrap<_, _, _> = HashWrap::new_checked(&mut store);
+        wrap.insert(5, "Five".to_string());
+        assert!(wrap.try_commit().is_ok());
+        assert_eq!(store.map[&5], "Five");
+
+        let mut wrap: HashWrap<_, _, _> = HashWrap::new_checked(&mut store);
+        wrap.insert(6, "Six".to_string());
+        wrap.inner.generation += 1;
+        let err = wrap.try_commit().unwrap_err();
+        assert_eq!(err.keys, vec![6]);
+        assert!(!store.map.contains_key(&6));
+    }
+
+    struct Contended {
+        map: HashMap<i32, String>,
+        generation: Rc<Cell<u64>>,
+    }
+
+    impl HashLike<i32, String> for Contended {
+        fn get(&self, k: &i32) -> Option<&String> {
+            self.map.get(k)
+        }
+
+        fn insert(&mut self, k: i32, v: String) -> Option<String> {
+            self.generation.set(self.generation.get() + 1);
+            self.map.insert(k, v)
+        }
+
+        fn remove(&mut self, k: &i32) -> Option<String> {
+            self.generation.set(self.generation.get() + 1);
+            self.map.remove(k)
+        }
+
+        fn contains_key(&self, k: &i32) -> bool {
+            self.map.contains_key(k)
+        }
+    }
+
+    impl Generation for Contended {
+        fn generation(&self) -> u64 {
+            self.generation.get()
+        }
+    }
+
+    #[test]
+    fn retried_commit() {
+        let writer = Rc::new(Cell::new(0));
+        let mut store = Contended {
+            map: get_hash(),
+            generation: writer.clone(),
+        };
+        // Another writer commits after the first attempt has read the store.
+        let mut attempts = 0;
+        let res = retry_on_conflict(&mut store, 3, |s| {
+            attempts += 1;
+            let mut cs = Changeset {
+                added: HashMap::new(),
+                removed: HashSet::new(),
+            };
+            cs.added.insert(5, format!("{} five", s.map.len()));
+            cs.removed.insert(0);
+            if attempts == 1 {
+                writer.set(writer.get() + 1);
+            }
+            cs
+        });
+        assert_eq!(res, Ok(()));
+        assert_eq!(attempts, 2);
+        assert_eq!(store.map[&5], "3 five");
+        assert!(!store.map.contains_key(&0));
+
+        let mut attempts = 0;
+        let res = retry_on_conflict(&mut store, 2, |_| {
+            attempts += 1;
+            writer.set(writer.get() + 1);
+            let mut cs = Changeset {
+                added: HashMap::new(),
+                removed: HashSet::new(),
+            };
+            cs.added.insert(6, "Six".to_string());
+            cs
+        });
+        assert_eq!(res, Err(TxError::Conflict(vec![6])));
+        assert_eq!(attempts, 3);
+        assert!(!store.map.contains_key(&6));
+    }
+
+    #[test]
+    fn undo_redo() {
+        let mut map = get_hash();
+        let mut wrap = HashWrap::new(&mut map).with_undo();
+        wrap.insert(5, "Five".to_string());
+        wrap.remove(&0);
+        *wrap.get_mut(&1).unwrap() = "Uno".to_string();
+        *wrap.get_mut(&1).unwrap() += "!";
+        assert!(wrap.undo());
+        assert_eq!(wrap.get(&1), Some(&"Uno".to_string()));
+        assert!(wrap.undo());
+        assert_eq!(wrap.get(&1), Some(&"One".to_string()));
+        assert!(wrap.undo());
+        assert_eq!(wrap.get(&0), Some(&"Zero".to_string()));
+        assert!(wrap.redo());
+        assert!(!wrap.contains_key(&0));
+        assert!(wrap.redo());
+        assert_eq!(wrap.get(&1), Some(&"Uno".to_string()));
+        wrap.insert(2, "Dos".to_string());
+        assert!(!wrap.redo());
+        assert!(wrap.undo());
+        assert!(wrap.undo());
+        assert!(wrap.undo());
+        assert!(wrap.undo());
+        assert!(!wrap.undo());
+        assert!(wrap.redo());
+        wrap.commit();
+        let mut expected = get_hash();
+        expected.insert(5, "Five".to_string());
+        assert_eq!(map, expected);
+    }
+
+    #[test]
+    fn undo_is_opt_in() {
+        let mut map = get_hash();
+        let mut wrap = HashWrap::new(&mut map);
+        wrap.insert(5, "Five".to_string());
+        *wrap.get_mut(&1).unwrap() = "Uno".to_string();
+        wrap.clear();
+        assert!(!wrap.undo());
+        assert!(wrap.is_empty());
+        wrap.rollback();
+    }
+
+    #[test]
+    fn undo_every_edit() {
+        let mut map = get_hash();
+        let mut wrap = HashWrap::new(&mut map).with_undo();
+        wrap.insert(5, "Five".to_string());
+        wrap.remove_entry(&0);
+        wrap.remove_many(&[1, 9]);
+        wrap.get_or_insert_with(6, || "Six".to_string());
+        assert!(wrap.swap(&2, &5));
+        wrap.map_values(|v| v + "!");
+        wrap.retain(|k, _| *k != 2);
+        wrap.drain().count();
+        assert!(wrap.is_empty());
+        assert!(wrap.undo());
+        assert_eq!(wrap.len(), 2);
+        assert!(wrap.undo());
+        assert_eq!(wrap.get(&2), Some(&"Five!".to_string()));
+        assert!(wrap.undo());
+        assert_eq!(wrap.get(&6), Some(&"Six".to_string()));
+        assert!(wrap.undo());
+        assert_eq!(wrap.get(&2), Some(&"Two".to_string()));
+        assert_eq!(wrap.get(&5), Some(&"Five".to_string()));
+        assert!(wrap.undo());
+        assert!(!wrap.contains_key(&6));
+        assert!(wrap.undo());
+        assert!(wrap.contains_key(&1));
+        assert!(wrap.undo());
+        assert!(wrap.contains_key(&0));
+        wrap.clear();
+        assert!(wrap.is_empty());
+        assert!(wrap.undo());
+        assert!(wrap.undo());
+        assert!(!wrap.undo());
+        wrap.commit();
+        assert_eq!(map, get_hash());
+    }
+
+    #[test]
+    fn undo_after_rollback_to() {
+        let mut map = get_hash();
+        let mut wrap = HashWrap::new(&mut map).with_undo();
+        wrap.insert(6, "Six".to_string());
+        let sp = wrap.savepoint();
+        wrap.insert(5, "a".to_string());
+        wrap.insert(5, "b".to_string());
+        wrap.rollback_to(sp).unwrap();
+        assert!(!wrap.undo());
+        assert!(!wrap.contains_key(&5));
+        wrap.checkpoint("start");
+        wrap.insert(5, "c".to_string());
+        assert!(wrap.rollback_to_label("start"));
+        assert!(!wrap.undo());
+        assert!(!wrap.redo());
+        wrap.commit();
+        assert!(!map.contains_key(&5));
+        assert_eq!(map[&6], "Six");
+    }
+
+    #[test]
+    fn standalone_diff() {
+        let base = get_hash();
+        let mut target = get_hash();
+        target.remove(&0);
+        target.insert(1, "Uno".to_string());
+        target.insert(5, "Five".to_string());
+        let cs = diff(&base, &target);
+        assert_eq!(cs.added.len(), 2);
+        assert_eq!(cs.removed, vec![0].into_iter().collect());
+        let mut map = base.clone();
+        apply_diff(&mut map, &cs);
+        assert_eq!(map, target);
+        assert!(diff(&target, &target).added.is_empty());
+    }
+
+    #[test]
+    fn merge_strategies() {
+        fn staged_then_changed(
+            map: &mut HashMap<i32, String>,
+            strategy: MergeStrategy<i32, String>,
+        ) {
+            let mut wrap = HashWrap::with_baseline(map);
+            wrap.insert(0, "Ours".to_string());
+            wrap.insert(1, "Uno".to_string());
+            wrap.insert(5, "Five".to_string());
+            wrap.inner.insert(0, "Theirs".to_string());
+            wrap.commit_with_strategy(strategy);
+        }
+
+        let mut map = get_hash();
+        staged_then_changed(&mut map, MergeStrategy::OursWins);
+        assert_eq!(map[&0], "Ours");
+        assert_eq!(map[&1], "Uno");
+        assert_eq!(map[&5], "Five");
+
+        let mut map = get_hash();
+        staged_then_changed(&mut map, MergeStrategy::TheirsWins);
+        assert_eq!(map[&0], "Theirs");
+        assert_eq!(map[&1], "Uno");
+        assert_eq!(map[&5], "Five");
+
+        let mut map = get_hash();
+        let strategy =
+            MergeStrategy::Custom(Box::new(|_, live: &String, ours| live.clone() + &ours));
+        staged_then_changed(&mut map, strategy);
+        assert_eq!(map[&0], "TheirsOurs");
+        assert_eq!(map[&1], "Uno");
+        assert_eq!(map[&5], "Five");
+
+        let mut map = get_hash();
+        let mut wrap = HashWrap::new(&mut map);
+        wrap.insert(1, "Uno".to_string());
+        wrap.insert(5, "Five".to_string());
+        wrap.commit_with_strategy(MergeStrategy::TheirsWins);
+        assert_eq!(map[&1], "One");
+        assert_eq!(map[&5], "Five");
+    }
+
+    #[test]
+    fn theirs_wins_keeps_removed_conflicts() {
+        let mut map = get_hash();
+        let mut wrap = HashWrap::with_baseline(&mut map);
+        wrap.remove(&1);
+        wrap.remove(&2);
+        wrap.inner.insert(2, "Deux".to_string());
+        wrap.commit_with_strategy(MergeStrategy::TheirsWins);
+        assert!(!map.contains_key(&1));
+        assert_eq!(map[&2], "Deux");
+    }
+
+    #[test]
+    fn checked_against_baseline() {
+        let mut map = get_hash();
+        let baseline = map.clone();
+        let mut wrap = HashWrap::new(&mut map);
+        wrap.insert(0, "Nil".to_string());
+        wrap.remove(&1);
+        wrap.insert(5, "Five".to_string());
+        wrap.inner.insert(2, "Deux".to_string());
+        assert_eq!(wrap.commit_checked(&baseline), Ok(()));
+        assert_eq!(map[&0], "Nil");
+        assert!(!map.contains_key(&1));
+
+        let baseline = map.clone();
+        let mut wrap = HashWrap::new(&mut map);
+        wrap.insert(0, "Zero".to_string());
+        wrap.remove(&2);
+        wrap.insert(6, "Six".to_string());
+        wrap.inner.insert(2, "Dos".to_string());
+        wrap.inner.insert(6, "Sechs".to_string());
+        let mut conflicts = wrap.commit_checked(&baseline).unwrap_err();
+        conflicts.sort();
+        assert_eq!(conflicts, vec![2, 6]);
+        assert_eq!(map[&0], "Nil");
+        assert_eq!(map[&6], "Sechs");
+    }
+
+    #[test]
+    fn tx_errors() {
+        fn staged(wrap: &HashWrap<i32, String>) -> Result<String, TxError<i32>> {
+            Ok(wrap.try_get(&0)?.clone())
+        }
+
+        let mut map = get_hash();
+        let wrap = HashWrap::new(&mut map);
+        assert_eq!(staged(&wrap), Ok("Zero".to_string()));
+        assert_eq!(wrap.try_close(), Ok(()));
+
+        let mut wrap = HashWrap::new(&mut map);
+        wrap.remove(&0);
+        assert_eq!(staged(&wrap), Err(TxError::KeyRemoved));
+        assert_eq!(
+            TxError::<i32>::from(wrap.try_get(&9).unwrap_err()),
+            TxError::KeyAbsent
+        );
+        assert_eq!(wrap.try_close(), Err(TxError::Unfinalised));
+        assert_eq!(map, get_hash());
+
+        let mut store = Versioned {
+            map: get_hash(),
+            generation: 0,
+        };
+        let mut wrap: HashWrap<_, _, _> = HashWrap::new_checked(&mut store);
+        wrap.insert(6, "Six".to_string());
+        wrap.inner.generation += 1;
+        let err: TxError<i32> = wrap.try_commit().unwrap_err().into();
+        assert_eq!(err, TxError::Conflict(vec![6]));
+    }
+
+    #[test]
+    fn borrowed_lookup() {
+        let mut map = HashMap::new();
+        map.insert("Zero".to_string(), 0);
+        map.insert("One".to_string(), 1);
+        let mut wrap = HashWrap::new(&mut map);
+        wrap.insert("Two".to_string(), 2);
+        wrap.remove(&"Zero".to_string());
+        assert_eq!(wrap.get_borrowed("One"), Some(&1));
+        assert_eq!(wrap.get_borrowed("Two"), Some(&2));
+        assert!(wrap.get_borrowed("Zero").is_none());
+        assert!(wrap.contains_key_borrowed("Two"));
+        assert!(!wrap.contains_key_borrowed("Zero"));
+        wrap.rollback();
+    }
+
+    #[test]
+    fn changeset_round_trip() {
+        let mut first = get_hash();
+        let mut second = get_hash();
+        let mut wrap = HashWrap::new(&mut first);
+        wrap.insert(5, "Five".to_string());
+        wrap.insert(1, "Uno".to_string());
+        wrap.remove(&0);
+        let (added, removed) = (wrap.added.clone(), wrap.removed.clone());
+        wrap.commit();
+        let wrap: HashWrap<_, _> = HashWrap::from_changeset(&mut second, added, removed);
+        assert_eq!(wrap.len(), 3);
+        wrap.commit();
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn preview_commit() {
+        let mut map = get_hash();
+        let mut wrap = HashWrap::new(&mut map);
+        wrap.insert(5, "Five".to_string());
+        wrap.insert(1, "Uno".to_string());
+        wrap.remove(&0);
+        let preview = wrap.preview();
+        wrap.rollback();
+        check_hash(map.clone());
+
+        let mut wrap = HashWrap::new(&mut map);
+        wrap.insert(5, "Five".to_string());
+        wrap.insert(1, "Uno".to_string());
+        wrap.remove(&0);
+        wrap.commit();
+        assert_eq!(preview, map);
+    }
+
+    #[test]
+    fn revert_single_key() {
+        let mut map = get_hash();
+        let mut wrap = HashWrap::new(&mut map);
+        wrap.insert(1, "Uno".to_string());
+        wrap.insert(5, "Five".to_string());
+        wrap.remove(&2);
+        wrap.revert_key(&1);
+        wrap.revert_key(&5);
+        wrap.revert_key(&2);
+        assert_eq!(wrap[&1], "One");
+        assert!(!wrap.contains_key(&5));
+        assert_eq!(wrap[&2], "Two");
+        wrap.commit();
+        check_hash(map);
+    }
+
+    #[test]
+    fn commit_hook_fires_once() {
+        let calls = Cell::new(0);
+        let mut map = get_hash();
+        let mut wrap = HashWrap::new(&mut map).with_commit_hook(|added, removed| {
+            assert!(added.contains_key(&5));
+            assert!(removed.contains(&0));
+            calls.set(calls.get() + 1);
+        });
+        wrap.insert(5, "Five".to_string());
+        wrap.remove(&0);
+        wrap.commit();
+        assert_eq!(calls.get(), 1);
+
+        let writes = Rc::new(Cell::new(0));
+        let mut store = Contended {
+            map: get_hash(),
+            generation: writes.clone(),
+        };
+        let seen = writes.clone();
+        let mut wrap: HashWrap<_, _, _> = HashWrap::new(&mut store)
+            .with_commit_hook(move |added, _| assert_eq!(seen.get(), added.len() as u64));
+        wrap.insert(5, "Five".to_string());
+        wrap.insert(6, "Six".to_string());
+        wrap.commit();
+        assert_eq!(writes.get(), 2);
+
+        let mut wrap = HashWrap::new(&mut map).with_commit_hook(|_, _| calls.set(calls.get() + 1));
+        wrap.insert(6, "Six".to_string());
+        wrap.rollback();
+        let wrap: HashWrap<_, _, _, commit_behavior::ImplicitRollback> =
+            HashWrap::new(&mut map).with_commit_hook(|_, _| calls.set(calls.get() + 1));
+        drop(wrap);
+        assert_eq!(calls.get(), 1);
+    }
+
+    #[test]
+    fn view_equality() {
+        let mut map = get_hash();
+        let mut wrap = HashWrap::new(&mut map);
+        assert!(wrap.eq_view(&get_hash()));
+        wrap.insert(1, "Uno".to_string());
+        wrap.insert(5, "Five".to_string());
+        wrap.remove(&0);
+        let mut expected = get_hash();
+        expected.insert(1, "Uno".to_string());
+        expected.insert(5, "Five".to_string());
+        assert!(!wrap.eq_view(&expected));
+        expected.remove(&0);
+        assert!(wrap.eq_view(&expected));
+        wrap.commit();
+        assert_eq!(map, expected);
+    }
+
+    #[test]
+    fn value_lookup() {
+        let mut map = get_hash();
+        let mut wrap = HashWrap::new(&mut map);
+        assert!(wrap.contains_value(&"Two".to_string()));
+        wrap.remove(&2);
+        assert!(!wrap.contains_value(&"Two".to_string()));
+        wrap.insert(1, "Uno".to_string());
+        assert!(wrap.contains_value(&"Uno".to_string()));
+        assert!(!wrap.contains_value(&"One".to_string()));
+        wrap.rollback();
+    }
+
+    #[test]
+    fn independent_snapshot() {
+        let mut map = get_hash();
+        let mut copy = get_hash();
+        let mut wrap = HashWrap::new(&mut map);
+        wrap.insert(5, "Five".to_string());
+        wrap.remove(&0);
+        let snapshot = wrap.snapshot();
+        wrap.insert(6, "Six".to_string());
+        wrap.get_mut(&5).unwrap().push_str("00");
+        assert_eq!(snapshot.added.len(), 1);
+        assert_eq!(snapshot.added[&5], "Five");
+        assert!(snapshot.removed.contains(&0));
+        wrap.rollback();
+
+        let mut branch = HashWrap::new(&mut copy);
+        branch.merge(snapshot);
+        let mut expected = get_hash();
+        expected.insert(5, "Five".to_string());
+        expected.remove(&0);
+        assert!(branch.eq_view(&expected));
+        branch.rollback();
+        check_hash(map);
     }
 
-    fn check_hash(val: HashMap<i32, String>) {
-        let r = get_hash();
-        assert_eq!(val.len(), r.len());
-        for (k, v) in val {
-            assert_eq!(v, r[&k]);
-        }
+    #[test]
+    fn drop_while_unwinding() {
+        let result = std::panic::catch_unwind(|| {
+            let mut map = get_hash();
+            let mut wrap = HashWrap::new(&mut map);
+            wrap.insert(5, "Five".to_string());
+            panic!("Inner failure");
+        });
+        let err = result.unwrap_err();
+        assert_eq!(err.downcast_ref::<&str>(), Some(&"Inner failure"));
     }
 
     #[test]
-    fn basic_shadowing() {
+    fn peek_staging() {
         let mut map = get_hash();
-        let wrap = HashWrap::new(&mut map);
-        assert_eq!(wrap[&1], "One");
+        let mut wrap = HashWrap::new(&mut map);
+        wrap.insert(1, "Uno".to_string());
+        wrap.remove(&0);
+        assert_eq!(wrap.staged_insertions().len(), 1);
+        assert_eq!(wrap.staged_insertions()[&1], "Uno");
+        assert_eq!(wrap.staged_removals().len(), 1);
+        assert!(wrap.staged_removals().contains(&0));
         wrap.commit();
-        check_hash(map);
+        assert_eq!(map[&1], "Uno");
     }
 
     #[test]
-    fn basic_rollback() {
+    fn batched_get_mut() {
         let mut map = get_hash();
         let mut wrap = HashWrap::new(&mut map);
-        assert_eq!(wrap[&2], "Two");
-        let ret = wrap.insert(5, "Five".to_string());
-        assert!(ret.is_none());
-        assert_eq!(wrap[&5], "Five");
-        let ret = wrap.remove(&0);
-        assert!(ret.is_some());
-        assert_eq!(ret.unwrap(), "Zero");
+        wrap.insert(5, "Five".to_string());
+        assert!(wrap.get_many_mut([&0, &0]).is_none());
+        assert!(wrap.get_many_mut([&0, &9]).is_none());
+        let [zero, two, five] = wrap.get_many_mut([&0, &2, &5]).unwrap();
+        zero.push_str("00");
+        two.push_str("22");
+        five.push_str("55");
+        wrap.commit();
+        assert_eq!(map[&0], "Zero00");
+        assert_eq!(map[&1], "One");
+        assert_eq!(map[&2], "Two22");
+        assert_eq!(map[&5], "Five55");
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serialize_changeset() {
+        let mut map = get_hash();
+        let mut wrap = HashWrap::new(&mut map);
+        wrap.insert(5, "Five".to_string());
+        wrap.remove(&0);
+        let json = serde_json::to_value(wrap.snapshot()).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({"added": {"5": "Five"}, "removed": [0]})
+        );
+        wrap.rollback();
+    }
+
+    #[test]
+    fn malformed_changeset() {
+        let mut map = get_hash();
+        let mut wrap = HashWrap::new(&mut map);
+        let mut cs = Changeset {
+            added: HashMap::new(),
+            removed: HashSet::new(),
+        };
+        cs.added.insert(1, "Uno".to_string());
+        cs.added.insert(5, "Five".to_string());
+        cs.removed.insert(1);
+        wrap.apply_changeset(cs);
+        wrap.commit();
+        assert!(!map.contains_key(&1));
+        assert_eq!(map[&5], "Five");
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn deserialize_changeset() {
+        let mut first = get_hash();
+        let mut second = get_hash();
+        let mut wrap = HashWrap::new(&mut first);
+        wrap.insert(5, "Five".to_string());
+        wrap.insert(1, "Uno".to_string());
+        wrap.remove(&0);
+        let json = serde_json::to_string(&wrap.snapshot()).unwrap();
+        wrap.commit();
+        let cs: Changeset<i32, String> = serde_json::from_str(&json).unwrap();
+        let mut wrap = HashWrap::new(&mut second);
+        wrap.apply_changeset(cs);
+        wrap.commit();
+        assert_eq!(first, second);
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn commit_emits_event() {
+        use std::sync::{Arc, Mutex};
+        use tracing::{
+            field::{Field, Visit},
+            span, Event, Metadata, Subscriber,
+        };
+
+        struct Messages(Vec<String>);
+
+        impl Visit for Messages {
+            fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+                if field.name() == "message" {
+                    self.0.push(format!("{:?}", value));
+                }
+            }
+        }
+
+        struct Capture(Arc<Mutex<Vec<String>>>);
+
+        impl Subscriber for Capture {
+            fn enabled(&self, _: &Metadata) -> bool {
+                true
+            }
+
+            fn new_span(&self, _: &span::Attributes) -> span::Id {
+                span::Id::from_u64(1)
+            }
+
+            fn record(&self, _: &span::Id, _: &span::Record) {}
+
+            fn record_follows_from(&self, _: &span::Id, _: &span::Id) {}
+
+            fn event(&self, event: &Event) {
+                let mut messages = Messages(Vec::new());
+                event.record(&mut messages);
+                self.0.lock().unwrap().extend(messages.0);
+            }
+
+            fn enter(&self, _: &span::Id) {}
+
+            fn exit(&self, _: &span::Id) {}
+        }
+
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        tracing::subscriber::with_default(Capture(seen.clone()), || {
+            let mut map = get_hash();
+            let mut wrap = HashWrap::new(&mut map);
+            wrap.insert(5, "Five".to_string());
+            wrap.commit();
+        });
+        assert_eq!(*seen.lock().unwrap(), vec!["commit".to_string()]);
+    }
+
+    #[test]
+    fn read_only_view() {
+        let mut map = get_hash();
+        let mut wrap = HashWrap::new(&mut map);
+        wrap.insert(5, "Five".to_string());
+        wrap.remove(&0);
+        {
+            let view = wrap.view();
+            assert_eq!(view.len(), 3);
+            assert!(view.contains_key(&5));
+            assert!(!view.contains_key(&0));
+            assert_eq!(view.get(&1).unwrap(), "One");
+            assert!(view.iter().all(|(k, _)| *k != 0));
+        }
         wrap.rollback();
         check_hash(map);
     }
 
     #[test]
-    fn basic_edits() {
+    fn resolve_on_commit() {
         let mut map = get_hash();
         let mut wrap = HashWrap::new(&mut map);
-        let ret = wrap.insert(5, "Five".to_string());
-        assert!(ret.is_none());
-        wrap.get_mut(&2).unwrap().push_str("00");
+        wrap.insert(1, "Uno".to_string());
+        wrap.insert(5, "Five".to_string());
+        wrap.commit_with(|k, current, staged| {
+            assert_eq!(*k, 1);
+            format!("{}/{}", current, staged)
+        });
+        assert_eq!(map[&1], "One/Uno");
+        assert_eq!(map[&5], "Five");
+    }
+
+    #[test]
+    fn atomic_bulk_insert() {
+        let mut map = get_hash();
+        let mut wrap = HashWrap::new(&mut map);
+        wrap.insert(5, "Five".to_string());
+        wrap.remove(&0);
+        let before = wrap.snapshot();
+        let ret = wrap.try_insert_all(
+            vec![
+                (0, "Nil".to_string()),
+                (6, "".to_string()),
+                (7, "Seven".to_string()),
+            ],
+            |_, v| if v.is_empty() { Err("empty") } else { Ok(()) },
+        );
+        assert_eq!(ret, Err("empty"));
+        assert_eq!(wrap.snapshot(), before);
+        let ret = wrap.try_insert_all(vec![(7, "Seven".to_string())], |_, _| Ok::<_, ()>(()));
+        assert!(ret.is_ok());
         wrap.commit();
-        assert!(map.contains_key(&5));
-        assert_eq!(map[&2], "Two00");
+        assert_eq!(map[&7], "Seven");
+        assert!(!map.contains_key(&0));
     }
 
     #[test]
-    fn basic_removal() {
+    fn index_assignment() {
         let mut map = get_hash();
         let mut wrap = HashWrap::new(&mut map);
-        let ret = wrap.remove(&1);
-        assert!(ret.is_some());
-        assert_eq!(ret.unwrap(), "One");
+        wrap[&2].push('x');
+        wrap[&1] = "Uno".to_string();
+        wrap.commit();
+        assert_eq!(map[&1], "Uno");
+        assert_eq!(map[&2], "Twox");
+    }
+
+    #[test]
+    #[should_panic]
+    fn index_removed_key() {
+        let mut map = get_hash();
+        let mut wrap = HashWrap::new(&mut map);
+        wrap.remove(&2);
+        wrap[&2].push('x');
+    }
+
+    #[test]
+    fn bulk_remove() {
+        let mut map = get_hash();
+        let mut wrap = HashWrap::new(&mut map);
+        wrap.insert(5, "Five".to_string());
+        wrap.remove(&1);
+        assert_eq!(wrap.remove_many(&[0, 1, 5, 9, 0]), 2);
+        assert!(!wrap.contains_key(&0));
+        assert!(!wrap.contains_key(&5));
         wrap.commit();
+        assert_eq!(map.len(), 1);
+        assert!(!map.contains_key(&0));
         assert!(!map.contains_key(&1));
+        assert!(!map.contains_key(&5));
     }
 
     #[test]
-    fn repeated_insertion() {
+    fn remove_with_key() {
         let mut map = get_hash();
         let mut wrap = HashWrap::new(&mut map);
-        let ret = wrap.insert(1, "Five".to_string());
-        assert!(ret.is_some());
-        assert_eq!(ret.unwrap(), "One");
-        let ret = wrap.insert(1, "Three".to_string());
-        assert!(ret.is_some());
-        assert_eq!(ret.unwrap(), "Five");
-        let ret = wrap.insert(1, "Four".to_string());
-        assert!(ret.is_some());
-        assert_eq!(ret.unwrap(), "Three");
-        let ret = wrap.remove(&1);
-        assert!(ret.is_some());
-        assert_eq!(ret.unwrap(), "Four");
-        wrap.rollback();
+        wrap.insert(5, "Five".to_string());
+        assert_eq!(wrap.remove_entry(&5), Some((5, "Five".to_string())));
+        assert_eq!(wrap.remove_entry(&1), Some((1, "One".to_string())));
+        assert_eq!(wrap.remove_entry(&1), None);
+        wrap.commit();
+        assert!(!map.contains_key(&1));
+        assert!(!map.contains_key(&5));
+    }
+
+    #[test]
+    fn replay_onto_copy() {
+        let mut map = get_hash();
+        let mut copy = get_hash();
+        let mut wrap = HashWrap::new(&mut map);
+        wrap.insert(5, "Five".to_string());
+        wrap.insert(1, "Uno".to_string());
+        wrap.remove(&0);
+        wrap.commit_into(&mut copy);
+        check_hash(map.clone());
+
+        let mut wrap = HashWrap::new(&mut map);
+        wrap.insert(5, "Five".to_string());
+        wrap.insert(1, "Uno".to_string());
+        wrap.remove(&0);
+        wrap.commit();
+        assert_eq!(map, copy);
+    }
+
+    #[test]
+    fn discarded_changeset() {
+        let mut map = get_hash();
+        let mut wrap = HashWrap::new(&mut map);
+        wrap.insert(5, "Five".to_string());
+        wrap.remove(&0);
+        let staged = wrap.snapshot();
+        let discarded = wrap.rollback_reported();
+        assert_eq!(discarded, staged);
         check_hash(map);
     }
+
+    #[test]
+    fn staging_invariant() {
+        let mut map = get_hash();
+        let mut wrap = HashWrap::new(&mut map);
+        wrap.insert(5, "Five".to_string());
+        wrap.remove(&5);
+        wrap.insert(5, "Cinq".to_string());
+        wrap.get_mut(&1).unwrap().push_str("00");
+        wrap.remove(&1);
+        wrap.assert_consistent();
+        wrap.added.insert(0, "Nil".to_string());
+        wrap.removed.insert(0);
+        let ret =
+            std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| wrap.assert_consistent()));
+        assert!(ret.is_err());
+        wrap.rollback();
+    }
+
+    #[test]
+    fn value_provenance() {
+        let mut map = get_hash();
+        let mut wrap = HashWrap::new(&mut map);
+        wrap.insert(5, "Five".to_string());
+        wrap.remove(&0);
+        assert_eq!(
+            wrap.get_source(&5),
+            Some(Source::Staged(&"Five".to_string()))
+        );
+        assert_eq!(
+            wrap.get_source(&1),
+            Some(Source::Original(&"One".to_string()))
+        );
+        assert_eq!(wrap.get_source(&0), None);
+        assert_eq!(wrap.get_source(&9), None);
+        wrap.rollback();
+    }
+
+    struct Ordered(std::collections::BTreeMap<i32, String>);
+
+    impl HashLike<i32, String> for Ordered {
+        fn get(&self, k: &i32) -> Option<&String> {
+            self.0.get(k)
+        }
+
+        fn insert(&mut self, k: i32, v: String) -> Option<String> {
+            self.0.insert(k, v)
+        }
+
+        fn remove(&mut self, k: &i32) -> Option<String> {
+            self.0.remove(k)
+        }
+
+        fn contains_key(&self, k: &i32) -> bool {
+            self.0.contains_key(k)
+        }
+    }
+
+    impl<'b> IntoIterator for &'b Ordered {
+        type Item = (&'b i32, &'b String);
+        type IntoIter = std::collections::btree_map::Iter<'b, i32, String>;
+
+        fn into_iter(self) -> Self::IntoIter {
+            self.0.iter()
+        }
+    }
+
+    #[test]
+    fn ordered_iteration() {
+        let mut store = Ordered(get_hash().into_iter().collect());
+        store.0.insert(4, "Four".to_string());
+        let mut wrap: HashWrap<_, _, _> = HashWrap::new(&mut store);
+        wrap.insert(3, "Three".to_string());
+        wrap.insert(6, "Six".to_string());
+        wrap.insert(1, "Uno".to_string());
+        wrap.insert(-1, "Minus One".to_string());
+        wrap.remove(&2);
+        let view: Vec<_> = wrap.iter_ordered().map(|(k, v)| (*k, v.as_str())).collect();
+        assert_eq!(
+            view,
+            vec![
+                (-1, "Minus One"),
+                (0, "Zero"),
+                (1, "Uno"),
+                (3, "Three"),
+                (4, "Four"),
+                (6, "Six"),
+            ]
+        );
+        wrap.rollback();
+    }
+
+    #[test]
+    fn introspection() {
+        let mut map = get_hash();
+        let mut wrap = HashWrap::new(&mut map);
+        assert!(!wrap.is_finalised());
+        assert_eq!(wrap.behavior_name(), "PanicIfUnfinalised");
+        wrap.insert(5, "Five".to_string());
+        wrap.discard();
+        check_hash(map.clone());
+
+        let wrap: HashWrap<_, _, _, commit_behavior::ImplicitCommit> = HashWrap::new(&mut map);
+        assert_eq!(wrap.behavior_name(), "ImplicitCommit");
+        drop(wrap);
+
+        let mut wrap = HashWrap::new(&mut map);
+        wrap.insert(5, "Five".to_string());
+        wrap.remove(&0);
+        wrap.remove(&1);
+        assert_eq!(wrap.commit_and_len(), 2);
+        assert_eq!(map.len(), 2);
+    }
+
+    #[test]
+    fn switch_behavior() {
+        let mut map = get_hash();
+        let mut wrap = HashWrap::new(&mut map);
+        wrap.insert(5, "Five".to_string());
+        wrap.remove(&0);
+        let wrap = wrap.into_implicit_commit();
+        assert_eq!(wrap.behavior_name(), "ImplicitCommit");
+        drop(wrap);
+        assert_eq!(map[&5], "Five");
+        assert!(!map.contains_key(&0));
+
+        let mut wrap = HashWrap::new(&mut map);
+        wrap.insert(6, "Six".to_string());
+        drop(wrap.into_implicit_rollback());
+        assert!(!map.contains_key(&6));
+
+        let wrap: HashWrap<_, _, _, commit_behavior::ImplicitRollback> = HashWrap::new(&mut map);
+        wrap.into_panicking().rollback();
+    }
+
+    #[test]
+    fn presized_staging() {
+        let mut map = get_hash();
+        let mut wrap = HashWrap::with_capacity(&mut map, 16, 8);
+        assert!(wrap.added.capacity() >= 16);
+        assert!(wrap.removed.capacity() >= 8);
+        wrap.insert(5, "Five".to_string());
+        wrap.remove(&0);
+        wrap.commit();
+        assert_eq!(map.len(), 3);
+        assert_eq!(map[&5], "Five");
+    }
+
+    #[test]
+    fn reserve_staging() {
+        let mut map = get_hash();
+        let mut wrap = HashWrap::new(&mut map);
+        wrap.reserve(32);
+        assert!(wrap.added.capacity() >= 32);
+        assert!(wrap.removed.capacity() >= 32);
+        wrap.insert(5, "Five".to_string());
+        wrap.commit();
+        assert_eq!(map.len(), 4);
+    }
+
+    #[test]
+    fn fallible_lookup() {
+        let mut map = get_hash();
+        let mut wrap = HashWrap::new(&mut map);
+        wrap.insert(5, "Five".to_string());
+        wrap.remove(&0);
+        assert_eq!(wrap.try_get(&5), Ok(&"Five".to_string()));
+        assert_eq!(wrap.try_get(&1), Ok(&"One".to_string()));
+        assert_eq!(wrap.try_get(&0), Err(KeyError::Removed));
+        assert_eq!(wrap.try_get(&9), Err(KeyError::Absent));
+        wrap.rollback();
+    }
+
+    #[test]
+    fn staged_removal_keys() {
+        let mut map = get_hash();
+        let mut wrap = HashWrap::new(&mut map);
+        wrap.insert(5, "Five".to_string());
+        wrap.insert(1, "Uno".to_string());
+        wrap.remove(&0);
+        wrap.remove(&2);
+        let mut keys: Vec<_> = wrap.removed_keys().cloned().collect();
+        keys.sort();
+        assert_eq!(keys, vec![0, 2]);
+        wrap.rollback();
+    }
+
+    #[test]
+    fn default_get_or_insert() {
+        let mut map = get_hash();
+        let mut wrap = HashWrap::new(&mut map);
+        assert_eq!(wrap.get_or_insert(1, "Uno".to_string()), "One");
+        wrap.remove(&2);
+        wrap.get_or_insert(2, "Deux".to_string()).push_str("00");
+        wrap.get_or_insert(5, "Five".to_string());
+        wrap.commit();
+        assert_eq!(map[&1], "One");
+        assert_eq!(map[&2], "Deux00");
+        assert_eq!(map[&5], "Five");
+    }
+
+    #[test]
+    fn replace_returns_previous() {
+        let mut map = get_hash();
+        let mut wrap = HashWrap::new(&mut map);
+        assert_eq!(wrap.replace(1, "Uno".to_string()), Some("One".to_string()));
+        assert_eq!(wrap.replace(1, "Eins".to_string()), Some("Uno".to_string()));
+        assert_eq!(wrap.replace(5, "Five".to_string()), None);
+        wrap.remove(&2);
+        assert_eq!(wrap.replace(2, "Deux".to_string()), None);
+        wrap.commit();
+        assert_eq!(map[&1], "Eins");
+        assert_eq!(map[&2], "Deux");
+    }
+
+    #[test]
+    fn conditional_commit() {
+        let mut map = get_hash();
+        let mut wrap = HashWrap::new(&mut map);
+        wrap.remove(&0);
+        assert!(!wrap.commit_if(|_, removed| removed.is_empty()));
+        check_hash(map.clone());
+
+        let mut wrap = HashWrap::new(&mut map);
+        wrap.insert(5, "Five".to_string());
+        assert!(wrap.commit_if(|added, removed| added.len() == 1 && removed.is_empty()));
+        assert_eq!(map[&5], "Five");
+    }
+
+    #[test]
+    fn diff_against_map() {
+        let mut map = get_hash();
+        let mut wrap = HashWrap::new(&mut map);
+        wrap.insert(5, "Five".to_string());
+        wrap.insert(1, "Uno".to_string());
+        wrap.remove(&0);
+        let mut other = get_hash();
+        other.insert(6, "Six".to_string());
+        let mut expected = MapDiff {
+            only_in_view: HashMap::new(),
+            only_in_other: HashMap::new(),
+            differing: HashMap::new(),
+        };
+        expected.only_in_view.insert(5, "Five".to_string());
+        expected.only_in_other.insert(0, "Zero".to_string());
+        expected.only_in_other.insert(6, "Six".to_string());
+        expected
+            .differing
+            .insert(1, ("Uno".to_string(), "One".to_string()));
+        assert_eq!(wrap.diff(&other), expected);
+        wrap.rollback();
+    }
+
+    #[test]
+    fn transform_values() {
+        let mut map: HashMap<i32, i32> = (0..4).map(|i| (i, i)).collect();
+        let mut wrap = HashWrap::new(&mut map);
+        wrap.insert(4, 4);
+        wrap.remove(&0);
+        wrap.map_values(|v| v * 2);
+        assert_eq!(wrap[&3], 6);
+        wrap.rollback();
+        assert_eq!(map[&3], 3);
+
+        let mut wrap = HashWrap::new(&mut map);
+        wrap.insert(4, 4);
+        wrap.remove(&0);
+        wrap.map_values(|v| v * 2);
+        wrap.commit();
+        let expected: HashMap<i32, i32> = (1..5).map(|i| (i, i * 2)).collect();
+        assert_eq!(map, expected);
+    }
+
+    #[test]
+    fn swap_values() {
+        let mut map = get_hash();
+        let mut wrap = HashWrap::new(&mut map);
+        assert!(wrap.swap(&0, &1));
+        wrap.remove(&2);
+        assert!(!wrap.swap(&0, &2));
+        assert!(!wrap.swap(&2, &1));
+        assert_eq!(wrap[&0], "One");
+        assert_eq!(wrap[&1], "Zero");
+        wrap.commit();
+        assert_eq!(map[&0], "One");
+        assert_eq!(map[&1], "Zero");
+        assert!(!map.contains_key(&2));
+    }
+
+    #[test]
+    fn pending_keys() {
+        let mut map = get_hash();
+        let mut wrap = HashWrap::new(&mut map);
+        wrap.insert(5, "Five".to_string());
+        wrap.insert(1, "Uno".to_string());
+        wrap.remove(&0);
+        let mut dirty: Vec<_> = wrap.dirty_keys().cloned().collect();
+        dirty.sort();
+        assert_eq!(dirty, vec![0, 1, 5]);
+        wrap.rollback();
+    }
+
+    #[test]
+    fn readable_debug() {
+        let mut map = HashMap::new();
+        map.insert(0, "Zero".to_string());
+        map.insert(1, "One".to_string());
+        let mut wrap = HashWrap::new(&mut map);
+        wrap.insert(1, "Uno".to_string());
+        wrap.remove(&0);
+        assert_eq!(
+            format!("{:?}", wrap.debug_view()),
+            "{1: \"Uno\"} (1 staged insertions, 1 staged removals)"
+        );
+        wrap.rollback();
+    }
 }
@@ -1,38 +1,375 @@
-use std::{collections::HashSet, hash::Hash};
+use std::{
+    borrow::Borrow,
+    cell::Cell,
+    collections::{HashMap, HashSet},
+    hash::Hash,
+    marker::PhantomData,
+    mem,
+    ops::{Deref, DerefMut},
+    rc::Rc,
+    thread,
+};
 
 use lending_library::{LendingLibrary, Loan};
 
-pub struct LendingWrap<'a, K, V>
+use crate::{
+    hash_wrap::{commit_behavior, SavepointError, SavepointId, SpecDrop, TxError},
+    transaction::{Transaction, TwoPhase},
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutstandingLoansError<K> {
+    pub keys: Vec<K>,
+}
+
+impl<K> From<OutstandingLoansError<K>> for TxError<K> {
+    fn from(e: OutstandingLoansError<K>) -> Self {
+        TxError::OutstandingLoans(e.keys)
+    }
+}
+
+pub trait LendingLike<K, V>: Extend<(K, V)>
+where
+    K: Eq + Hash + Clone,
+{
+    type Loan: DerefMut<Target = V>;
+
+    fn contains_key(&self, k: &K) -> bool;
+    fn insert(&mut self, k: K, v: V) -> Option<V>;
+    fn remove(&mut self, k: &K) -> bool;
+    fn lend(&mut self, k: &K) -> Option<Self::Loan>;
+}
+
+impl<K, V> LendingLike<K, V> for LendingLibrary<K, V>
 where
-    K: Eq + Hash,
+    K: Eq + Hash + Clone,
 {
-    inner: &'a mut LendingLibrary<K, V>,
-    added: LendingLibrary<K, V>,
+    type Loan = Loan<K, V>;
+
+    fn contains_key(&self, k: &K) -> bool {
+        LendingLibrary::contains_key(self, k)
+    }
+
+    fn insert(&mut self, k: K, v: V) -> Option<V> {
+        LendingLibrary::insert(self, k, v)
+    }
+
+    fn remove(&mut self, k: &K) -> bool {
+        LendingLibrary::remove(self, k)
+    }
+
+    fn lend(&mut self, k: &K) -> Option<Loan<K, V>> {
+        LendingLibrary::lend(self, k)
+    }
+}
+
+// Where a staging library is left if it is dropped while loans from it are
+// still outstanding. Each loan holds the spot, so the library is freed by the
+// last of them once its value has been returned.
+type Parking<K, V> = Rc<Cell<Option<Box<LendingLibrary<K, V>>>>>;
+
+// A loan of a staged value. The staging keeps a marker per lent key and each
+// loan holds a count on it, so what is lent out can be read off the markers
+// without asking the library. Fields drop in order, so the value is returned
+// before the parked library can be freed.
+pub struct StagedLoan<K, V>
+where
+    K: Eq + Hash + Clone,
+{
+    loan: Loan<K, V>,
+    _lent: Rc<()>,
+    _parking: Parking<K, V>,
+}
+
+impl<K, V> Deref for StagedLoan<K, V>
+where
+    K: Eq + Hash + Clone,
+{
+    type Target = V;
+
+    fn deref(&self) -> &V {
+        &self.loan
+    }
+}
+
+impl<K, V> DerefMut for StagedLoan<K, V>
+where
+    K: Eq + Hash + Clone,
+{
+    fn deref_mut(&mut self) -> &mut V {
+        &mut self.loan
+    }
+}
+
+// The staging library along with the loans taken from it. It is boxed so that
+// loans can point into it while the wrap moves, and only read through `Deref`
+// so that every loan is counted.
+struct Staging<K, V>
+where
+    K: Eq + Hash + Clone,
+{
+    library: Box<LendingLibrary<K, V>>,
+    lent: HashMap<K, Rc<()>>,
+    parking: Parking<K, V>,
+}
+
+impl<K, V> Staging<K, V>
+where
+    K: Eq + Hash + Clone,
+{
+    fn new(library: LendingLibrary<K, V>) -> Self {
+        Staging {
+            library: Box::new(library),
+            lent: HashMap::new(),
+            parking: Rc::default(),
+        }
+    }
+
+    // A key is lent out while any loan still holds a count on its marker
+    // besides the staging's own.
+    fn busy_keys(&self) -> impl Iterator<Item = &K> {
+        self.lent
+            .iter()
+            .filter(|(_, lent)| Rc::strong_count(lent) > 1)
+            .map(|(k, _)| k)
+    }
+
+    fn is_lent(&self, k: &K) -> bool {
+        self.lent
+            .get(k)
+            .is_some_and(|lent| Rc::strong_count(lent) > 1)
+    }
+
+    fn insert(&mut self, k: K, v: V) -> Option<V> {
+        self.library.insert(k, v)
+    }
+
+    fn remove(&mut self, k: &K) -> bool {
+        self.library.remove(k)
+    }
+
+    // A value that is already lent out isn't lent again, or handed out
+    // mutably, so the library is never asked to.
+    fn get_mut(&mut self, k: &K) -> Option<&mut V> {
+        if self.is_lent(k) {
+            None
+        } else {
+            self.library.get_mut(k)
+        }
+    }
+
+    fn lend(&mut self, k: &K) -> Option<StagedLoan<K, V>> {
+        if self.is_lent(k) {
+            return None;
+        }
+        let loan = self.library.lend(k)?;
+        let lent = self.lent.entry(k.clone()).or_default().clone();
+        Some(StagedLoan {
+            loan,
+            _lent: lent,
+            _parking: self.parking.clone(),
+        })
+    }
+}
+
+impl<K, V> Deref for Staging<K, V>
+where
+    K: Eq + Hash + Clone,
+{
+    type Target = LendingLibrary<K, V>;
+
+    fn deref(&self) -> &LendingLibrary<K, V> {
+        &self.library
+    }
+}
+
+impl<K, V> Drop for Staging<K, V>
+where
+    K: Eq + Hash + Clone,
+{
+    fn drop(&mut self) {
+        if self.busy_keys().next().is_some() {
+            let library = mem::replace(&mut self.library, Box::new(LendingLibrary::new()));
+            self.parking.set(Some(library));
+        }
+    }
+}
+
+type Savepoint<K, V> = (SavepointId, Vec<(K, V)>, HashSet<K>);
+
+pub struct LendingWrap<'a, K, V, T = LendingLibrary<K, V>, B = commit_behavior::PanicIfUnfinalised>
+where
+    LendingWrap<'a, K, V, T, B>: SpecDrop,
+    K: Eq + Hash + Clone,
+    T: LendingLike<K, V>,
+    B: commit_behavior::Behavior,
+{
+    inner: &'a mut T,
+    added: Staging<K, V>,
     removed: HashSet<K>,
+    savepoints: Vec<Savepoint<K, V>>,
+    next_savepoint: usize,
+    commit_behaviour: PhantomData<B>,
+    finalised: bool,
 }
 
-impl<'a, K, V> LendingWrap<'a, K, V>
+// Other commit behaviours are reached through the `into_*` conversions.
+impl<'a, K, V, T> LendingWrap<'a, K, V, T>
 where
     K: Eq + Hash + Clone,
-    V: Clone,
+    T: LendingLike<K, V>,
 {
-    pub fn new(lib: &'a mut LendingLibrary<K, V>) -> Self {
+    pub fn new(lib: &'a mut T) -> Self {
         LendingWrap {
             inner: lib,
-            added: LendingLibrary::new(),
+            added: Staging::new(LendingLibrary::new()),
             removed: HashSet::new(),
+            savepoints: Vec::new(),
+            next_savepoint: 0,
+            commit_behaviour: PhantomData,
+            finalised: false,
         }
     }
+}
 
-    pub fn commit(self) {
-        for k in self.removed {
-            self.inner.remove(&k);
+impl<'a, K, V, T, B> LendingWrap<'a, K, V, T, B>
+where
+    LendingWrap<'a, K, V, T, B>: SpecDrop,
+    K: Eq + Hash + Clone,
+    T: LendingLike<K, V>,
+    B: commit_behavior::Behavior,
+{
+    // Lent values can't be applied until they are returned, so a commit with
+    // any outstanding is refused outright and has to go through `try_commit`
+    // instead. The staging is discarded as on rollback.
+    fn _commit(&mut self) {
+        let lent = self.busy_keys().next().is_some();
+        if lent {
+            self._rollback();
+        } else {
+            for k in self.removed.drain() {
+                self.inner.remove(&k);
+            }
+            let library = mem::replace(&mut self.added.library, Box::new(LendingLibrary::new()));
+            self.inner.extend(*library);
+        }
+        self.finalised = true;
+        if lent && !thread::panicking() {
+            panic!("Error: Committing while staged values are still lent out.")
         }
-        self.inner.extend(self.added);
     }
 
-    pub fn rollback(self) {}
+    pub fn commit(mut self) {
+        self._commit()
+    }
+
+    // Refuses to commit while any staged value is lent out, handing the wrap
+    // back with its staging as it was so the loans can be returned and the
+    // commit retried. The wrap is handed back unboxed, like the value of a
+    // refused `Rc::try_unwrap`.
+    #[allow(clippy::result_large_err)]
+    pub fn try_commit(mut self) -> Result<(), (Self, OutstandingLoansError<K>)> {
+        let keys: Vec<K> = self.busy_keys().cloned().collect();
+        if keys.is_empty() {
+            self._commit();
+            Ok(())
+        } else {
+            Err((self, OutstandingLoansError { keys }))
+        }
+    }
+
+    pub fn busy_keys(&self) -> impl Iterator<Item = &K> {
+        self.added.busy_keys()
+    }
+
+    // Values still lent out when the staging is discarded keep their loans
+    // valid, but whatever is done through them is discarded with it.
+    fn _rollback(&mut self) {
+        self.added = Staging::new(LendingLibrary::new());
+        self.removed.clear();
+        self.finalised = true;
+    }
+
+    pub fn rollback(mut self) {
+        self._rollback()
+    }
+
+    pub fn contains_key(&self, k: &K) -> bool {
+        !self.removed.contains(k) && (self.added.contains_key(k) || self.inner.contains_key(k))
+    }
+}
+
+// LendingLike has no way to walk its entries, so anything that needs the full
+// view is only available directly over a LendingLibrary.
+impl<'a, K, V, B> LendingWrap<'a, K, V, LendingLibrary<K, V>, B>
+where
+    LendingWrap<'a, K, V, LendingLibrary<K, V>, B>: SpecDrop,
+    K: Eq + Hash + Clone,
+    B: commit_behavior::Behavior,
+{
+    // LendingLike only looks up by &K, so borrowed-key queries need a
+    // LendingLibrary backing.
+    pub fn contains_key_borrowed<Q>(&self, k: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        !self.removed.contains(k) && (self.added.contains_key(k) || self.inner.contains_key(k))
+    }
+
+    pub fn len(&self) -> usize {
+        let removed = self
+            .removed
+            .iter()
+            .filter(|k| self.inner.contains_key(k))
+            .count();
+        let added = self
+            .added
+            .keys()
+            .filter(|k| !self.inner.contains_key(k))
+            .count();
+        self.inner.len() - removed + added
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &K> {
+        let (added, removed) = (&self.added, &self.removed);
+        self.inner
+            .keys()
+            .filter(move |k| !removed.contains(k) && !added.contains_key(k))
+            .chain(added.keys())
+    }
 
+    // Values that are currently lent out can't be read, so a view holding any
+    // compares unequal.
+    pub fn eq_view(&self, other: &HashMap<K, V>) -> bool
+    where
+        V: PartialEq,
+    {
+        let (added, removed) = (&self.added, &self.removed);
+        let visible: Vec<(&K, &V)> = self
+            .inner
+            .iter()
+            .filter(|(k, _)| !removed.contains(k) && !added.contains_key(k))
+            .chain(added.iter())
+            .collect();
+        visible.len() == self.len()
+            && visible.len() == other.len()
+            && visible.iter().all(|(k, v)| other.get(k) == Some(v))
+    }
+}
+
+impl<'a, K, V, T, B> LendingWrap<'a, K, V, T, B>
+where
+    LendingWrap<'a, K, V, T, B>: SpecDrop,
+    K: Eq + Hash + Clone,
+    T: LendingLike<K, V>,
+    V: Clone,
+    B: commit_behavior::Behavior,
+{
     pub fn insert(&mut self, k: K, v: V) -> Option<V> {
         if self.added.contains_key(&k) {
             self.added.insert(k, v)
@@ -42,8 +379,9 @@ where
                 self.added.insert(k, v)
             } else {
                 if self.inner.contains_key(&k) {
-                    let item = self.inner.lend(&k).unwrap();
-                    let ret = Some((*item).clone());
+                    // The previous value can't be read while it is lent out
+                    // of the underlying library, so it isn't reported.
+                    let ret = self.inner.lend(&k).map(|item| (*item).clone());
                     self.added.insert(k, v);
                     ret
                 } else {
@@ -54,10 +392,6 @@ where
         }
     }
 
-    pub fn contains_key(&self, k: &K) -> bool {
-        !self.removed.contains(k) && (self.added.contains_key(k) || self.inner.contains_key(k))
-    }
-
     pub fn remove(&mut self, k: &K) -> bool {
         if self.added.contains_key(k) {
             self.removed.insert(k.clone());
@@ -72,7 +406,46 @@ where
         }
     }
 
-    pub fn lend(&mut self, k: &K) -> Option<Loan<K, V>> {
+    pub fn remove_value(&mut self, k: &K) -> Option<V> {
+        if self.added.contains_key(k) {
+            let ret = self.added.lend(k).map(|item| (*item).clone());
+            self.removed.insert(k.clone());
+            self.added.remove(k);
+            ret
+        } else {
+            if self.removed.contains(k) {
+                None
+            } else {
+                self.removed.insert(k.clone());
+                self.inner.lend(k).map(|item| (*item).clone())
+            }
+        }
+    }
+
+    pub fn get_mut(&mut self, k: &K) -> Option<&mut V> {
+        if self.added.contains_key(k) {
+            self.added.get_mut(k)
+        } else {
+            if self.removed.contains(k) {
+                None
+            } else {
+                if self.inner.contains_key(k) {
+                    let item = self.inner.lend(k)?;
+                    self.added.insert(k.clone(), (*item).clone());
+                    self.added.get_mut(k)
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    // Lending a key that is not yet staged takes a transient loan from the
+    // underlying library to clone the value into the staging area. That loan
+    // is returned before this call does, so the underlying library is left as
+    // it was. A value already lent out of this wrap yields `None`; one lent
+    // out of the underlying library is up to its `lend`.
+    pub fn lend(&mut self, k: &K) -> Option<StagedLoan<K, V>> {
         if self.added.contains_key(k) {
             self.added.lend(k)
         } else {
@@ -80,7 +453,7 @@ where
                 None
             } else {
                 if self.inner.contains_key(k) {
-                    let item = self.inner.lend(&k).unwrap();
+                    let item = self.inner.lend(k)?;
                     self.added.insert(k.clone(), (*item).clone());
                     self.added.lend(k)
                 } else {
@@ -89,4 +462,547 @@ where
             }
         }
     }
+
+    pub fn savepoint(&mut self) -> Result<SavepointId, OutstandingLoansError<K>> {
+        let keys: Vec<K> = self.busy_keys().cloned().collect();
+        if !keys.is_empty() {
+            return Err(OutstandingLoansError { keys });
+        }
+        let id = SavepointId(self.next_savepoint);
+        self.next_savepoint += 1;
+        let added = self
+            .added
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+        self.savepoints.push((id, added, self.removed.clone()));
+        Ok(id)
+    }
+
+    // No savepoint is taken while loans are outstanding, so any still held
+    // were taken since this one and are discarded with the rest of that
+    // staging, as on a full rollback.
+    pub fn rollback_to(&mut self, id: SavepointId) -> Result<(), TxError<K>> {
+        let pos = self
+            .savepoints
+            .iter()
+            .rposition(|(sp, _, _)| *sp == id)
+            .ok_or(SavepointError)?;
+        let (_, added, removed) = &self.savepoints[pos];
+        let mut library = LendingLibrary::new();
+        library.extend(added.iter().cloned());
+        self.removed = removed.clone();
+        self.added = Staging::new(library);
+        self.savepoints.truncate(pos + 1);
+        Ok(())
+    }
+
+    // Never blocks: an item that is already lent out of this wrap yields
+    // `None` and stages nothing.
+    pub fn try_lend(&mut self, k: &K) -> Option<StagedLoan<K, V>> {
+        self.lend(k)
+    }
+
+    pub fn lend_checked(&mut self, k: &K) -> Result<StagedLoan<K, V>, TxError<K>> {
+        if self.removed.contains(k) {
+            Err(TxError::KeyRemoved)
+        } else {
+            if self.contains_key(k) {
+                self.lend(k)
+                    .ok_or_else(|| TxError::OutstandingLoans(vec![k.clone()]))
+            } else {
+                Err(TxError::KeyAbsent)
+            }
+        }
+    }
+}
+
+impl<'a, K, V, T, B> Extend<(K, V)> for LendingWrap<'a, K, V, T, B>
+where
+    LendingWrap<'a, K, V, T, B>: SpecDrop,
+    K: Eq + Hash + Clone,
+    T: LendingLike<K, V>,
+    V: Clone,
+    B: commit_behavior::Behavior,
+{
+    fn extend<I: IntoIterator<Item = (K, V)>>(&mut self, iter: I) {
+        for (k, v) in iter {
+            self.insert(k, v);
+        }
+    }
+}
+
+impl<'a, K, V, T, B> LendingLike<K, V> for LendingWrap<'a, K, V, T, B>
+where
+    LendingWrap<'a, K, V, T, B>: SpecDrop,
+    K: Eq + Hash + Clone,
+    T: LendingLike<K, V>,
+    V: Clone,
+    B: commit_behavior::Behavior,
+{
+    type Loan = StagedLoan<K, V>;
+
+    fn contains_key(&self, k: &K) -> bool {
+        LendingWrap::contains_key(self, k)
+    }
+
+    fn insert(&mut self, k: K, v: V) -> Option<V> {
+        LendingWrap::insert(self, k, v)
+    }
+
+    fn remove(&mut self, k: &K) -> bool {
+        LendingWrap::remove(self, k)
+    }
+
+    fn lend(&mut self, k: &K) -> Option<StagedLoan<K, V>> {
+        LendingWrap::lend(self, k)
+    }
+}
+
+impl<'a, K, V, T, B> Transaction for LendingWrap<'a, K, V, T, B>
+where
+    LendingWrap<'a, K, V, T, B>: SpecDrop,
+    K: Eq + Hash + Clone,
+    T: LendingLike<K, V>,
+    B: commit_behavior::Behavior,
+{
+    fn commit(self) {
+        LendingWrap::commit(self)
+    }
+
+    fn rollback(self) {
+        LendingWrap::rollback(self)
+    }
+}
+
+impl<'a, K, V, T, B> TwoPhase for LendingWrap<'a, K, V, T, B>
+where
+    LendingWrap<'a, K, V, T, B>: SpecDrop,
+    K: Eq + Hash + Clone,
+    T: LendingLike<K, V>,
+    B: commit_behavior::Behavior,
+{
+    fn prepare(&mut self) -> bool {
+        self.busy_keys().next().is_none()
+    }
+
+    fn apply(self: Box<Self>) {
+        LendingWrap::commit(*self)
+    }
+
+    fn abort(self: Box<Self>) {
+        LendingWrap::rollback(*self)
+    }
+}
+
+impl_into_behavior! {
+    LendingWrap<'a, K, V, T> {
+        take: removed, savepoints;
+        copy: next_savepoint;
+        move: added;
+    }
+    where { K: Eq + Hash + Clone, T: LendingLike<K, V> }
+    into_implicit_commit => ImplicitCommit;
+    into_implicit_rollback => ImplicitRollback;
+    into_panicking => PanicIfUnfinalised;
+}
+
+impl_spec_drop! {
+    LendingWrap<'a, K, V, T> where { K: Eq + Hash + Clone, T: LendingLike<K, V> }
+    ImplicitCommit => _commit;
+    ImplicitRollback => _rollback;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn get_lib() -> LendingLibrary<i32, String> {
+        let mut l = LendingLibrary::new();
+        l.insert(0, "Zero".to_string());
+        l.insert(1, "One".to_string());
+        l.insert(2, "Two".to_string());
+        l
+    }
+
+    #[test]
+    fn basic_commit() {
+        let mut lib = get_lib();
+        let mut wrap = LendingWrap::new(&mut lib);
+        wrap.insert(5, "Five".to_string());
+        wrap.remove(&0);
+        wrap.commit();
+        assert!(lib.contains_key(&5));
+        assert!(!lib.contains_key(&0));
+        assert_eq!(lib.len(), 3);
+    }
+
+    #[test]
+    #[should_panic(expected = "without calling commit or rollback")]
+    fn drop_unfinalised() {
+        let mut lib = get_lib();
+        let mut wrap = LendingWrap::new(&mut lib);
+        wrap.insert(5, "Five".to_string());
+    }
+
+    #[test]
+    fn rollback_returns_loans() {
+        let mut lib = get_lib();
+        let mut wrap = LendingWrap::new(&mut lib);
+        {
+            let mut loan = wrap.lend(&1).unwrap();
+            loan.push_str("00");
+        }
+        assert!(wrap.added.contains_key(&1));
+        wrap.remove(&2);
+        wrap.rollback();
+        assert_eq!(lib.len(), 3);
+        assert_eq!(*lib.lend(&1).unwrap(), "One");
+        assert!(lib.contains_key(&2));
+    }
+
+    #[test]
+    fn lend_already_lent() {
+        let mut lib = get_lib();
+        let mut outer = LendingWrap::new(&mut lib);
+        let loan = outer.lend(&1).unwrap();
+        assert!(outer.lend(&1).is_none());
+        assert!(outer.get_mut(&1).is_none());
+        {
+            let mut wrap = LendingWrap::new(&mut outer);
+            assert!(wrap.lend(&1).is_none());
+            assert!(wrap.insert(1, "Uno".to_string()).is_none());
+            assert_eq!(*wrap.lend(&1).unwrap(), "Uno");
+            wrap.rollback();
+        }
+        drop(loan);
+        assert_eq!(*outer.lend(&1).unwrap(), "One");
+        outer.rollback();
+    }
+
+    #[test]
+    fn staged_get_mut() {
+        let mut lib = get_lib();
+        let mut wrap = LendingWrap::new(&mut lib);
+        wrap.get_mut(&2).unwrap().push_str("00");
+        wrap.get_mut(&2).unwrap().push('!');
+        wrap.remove(&0);
+        assert!(wrap.get_mut(&0).is_none());
+        assert!(wrap.get_mut(&9).is_none());
+        wrap.commit();
+        assert_eq!(*lib.lend(&2).unwrap(), "Two00!");
+        assert!(!lib.contains_key(&0));
+    }
+
+    #[test]
+    fn remove_returns_value() {
+        let mut lib = get_lib();
+        let mut wrap = LendingWrap::new(&mut lib);
+        wrap.insert(5, "Five".to_string());
+        assert_eq!(wrap.remove_value(&5), Some("Five".to_string()));
+        assert_eq!(wrap.remove_value(&1), Some("One".to_string()));
+        assert_eq!(wrap.remove_value(&1), None);
+        assert_eq!(wrap.remove_value(&9), None);
+        wrap.commit();
+        assert_eq!(lib.len(), 2);
+        assert!(!lib.contains_key(&1));
+        assert!(!lib.contains_key(&5));
+    }
+
+    #[test]
+    fn commit_with_outstanding_loan() {
+        let mut lib = get_lib();
+        let mut wrap = LendingWrap::new(&mut lib);
+        wrap.insert(5, "Five".to_string());
+        let loan = wrap.lend(&1).unwrap();
+        let (wrap, err) = wrap.try_commit().unwrap_err();
+        assert_eq!(err, OutstandingLoansError { keys: vec![1] });
+        assert!(wrap.contains_key(&5));
+        drop(loan);
+        assert!(wrap.try_commit().is_ok());
+        assert!(lib.contains_key(&5));
+
+        let mut wrap = LendingWrap::new(&mut lib);
+        wrap.insert(6, "Six".to_string());
+        let loan = wrap.lend(&6).unwrap();
+        let (wrap, _) = wrap.try_commit().unwrap_err();
+        drop(loan);
+        wrap.rollback();
+        assert!(!lib.contains_key(&6));
+    }
+
+    #[test]
+    #[should_panic(expected = "still lent out")]
+    fn commit_with_loan() {
+        let mut lib = get_lib();
+        let mut wrap = LendingWrap::new(&mut lib).into_implicit_commit();
+        wrap.insert(5, "Five".to_string());
+        let _loan = wrap.lend(&5).unwrap();
+        drop(wrap);
+    }
+
+    #[test]
+    fn poll_lent_key() {
+        let mut lib = get_lib();
+        let mut outer = LendingWrap::new(&mut lib);
+        let loan = outer.lend(&2).unwrap();
+        {
+            let mut wrap = LendingWrap::new(&mut outer);
+            assert!(wrap.try_lend(&2).is_none());
+            assert!(!wrap.added.contains_key(&2));
+            {
+                let held = wrap.try_lend(&1).unwrap();
+                assert_eq!(*held, "One");
+                assert!(wrap.try_lend(&1).is_none());
+            }
+            assert!(wrap.try_lend(&1).is_some());
+            wrap.rollback();
+        }
+        drop(loan);
+        outer.rollback();
+    }
+
+    #[test]
+    fn effective_len() {
+        let mut lib = get_lib();
+        let loan = lib.lend(&2).unwrap();
+        let mut wrap = LendingWrap::new(&mut lib);
+        assert_eq!(wrap.len(), 3);
+        wrap.insert(5, "Five".to_string());
+        wrap.insert(1, "Uno".to_string());
+        wrap.remove(&0);
+        assert_eq!(wrap.len(), 3);
+        wrap.remove(&1);
+        wrap.remove(&2);
+        wrap.remove(&5);
+        assert!(wrap.is_empty());
+        wrap.rollback();
+        drop(loan);
+    }
+
+    #[test]
+    fn effective_keys() {
+        let mut lib = get_lib();
+        let mut wrap = LendingWrap::new(&mut lib);
+        wrap.insert(5, "Five".to_string());
+        wrap.insert(1, "Uno".to_string());
+        wrap.remove(&0);
+        let mut keys: Vec<_> = wrap.keys().cloned().collect();
+        keys.sort();
+        assert_eq!(keys, vec![1, 2, 5]);
+        wrap.rollback();
+    }
+
+    #[test]
+    fn borrowed_contains_key() {
+        let mut lib = LendingLibrary::new();
+        lib.insert("zero".to_string(), 0);
+        lib.insert("one".to_string(), 1);
+        let mut wrap = LendingWrap::new(&mut lib);
+        wrap.insert("five".to_string(), 5);
+        wrap.remove(&"zero".to_string());
+        assert!(wrap.contains_key_borrowed("one"));
+        assert!(wrap.contains_key_borrowed("five"));
+        assert!(!wrap.contains_key_borrowed("zero"));
+        assert!(!wrap.contains_key_borrowed("nine"));
+        wrap.rollback();
+    }
+
+    #[test]
+    fn savepoint_rollback() {
+        let mut lib = get_lib();
+        let mut wrap = LendingWrap::new(&mut lib);
+        wrap.insert(5, "Five".to_string());
+        let first = wrap.savepoint().unwrap();
+        wrap.insert(6, "Six".to_string());
+        wrap.remove(&0);
+        {
+            let _loan = wrap.lend(&5).unwrap();
+            assert_eq!(
+                wrap.savepoint(),
+                Err(OutstandingLoansError { keys: vec![5] })
+            );
+        }
+        wrap.rollback_to(first).unwrap();
+        assert!(!wrap.contains_key(&6));
+        assert!(wrap.contains_key(&0));
+        wrap.commit();
+        assert_eq!(lib.len(), 4);
+        assert_eq!(*lib.lend(&5).unwrap(), "Five");
+        assert!(!lib.contains_key(&6));
+    }
+
+    #[test]
+    fn rollback_to_with_loan() {
+        let mut lib = get_lib();
+        let mut wrap = LendingWrap::new(&mut lib);
+        wrap.insert(5, "Five".to_string());
+        let first = wrap.savepoint().unwrap();
+        wrap.insert(6, "Six".to_string());
+        let second = wrap.savepoint().unwrap();
+        let mut loan = wrap.lend(&6).unwrap();
+        loan.push_str("00");
+        assert_eq!(wrap.rollback_to(second), Ok(()));
+        assert_eq!(wrap.busy_keys().next(), None);
+        assert_eq!(*wrap.lend(&6).unwrap(), "Six");
+        loan.push('!');
+        assert_eq!(*loan, "Six00!");
+        drop(loan);
+        assert_eq!(wrap.rollback_to(first), Ok(()));
+        assert!(!wrap.contains_key(&6));
+        assert_eq!(wrap.rollback_to(second), Err(TxError::UnknownSavepoint));
+        wrap.commit();
+        assert!(lib.contains_key(&5));
+        assert!(!lib.contains_key(&6));
+    }
+
+    #[test]
+    fn rollback_with_loan() {
+        let mut lib = get_lib();
+        let mut wrap = LendingWrap::new(&mut lib);
+        wrap.insert(5, "Five".to_string());
+        let mut loan = wrap.lend(&5).unwrap();
+        wrap.rollback();
+        loan.push_str("00");
+        assert_eq!(*loan, "Five00");
+        drop(loan);
+        assert!(!lib.contains_key(&5));
+
+        let mut wrap = LendingWrap::new(&mut lib).into_implicit_rollback();
+        let first = wrap.lend(&1).unwrap();
+        let second = wrap.lend(&2).unwrap();
+        drop(wrap);
+        drop(second);
+        assert_eq!(*first, "One");
+        drop(first);
+        assert_eq!(lib.len(), 3);
+    }
+
+    #[test]
+    fn outstanding_loan_keys() {
+        let mut lib = get_lib();
+        let mut wrap = LendingWrap::new(&mut lib);
+        wrap.insert(5, "Five".to_string());
+        assert_eq!(wrap.busy_keys().next(), None);
+        let loan = wrap.lend(&1).unwrap();
+        let other = wrap.lend(&5).unwrap();
+        let mut busy: Vec<_> = wrap.busy_keys().collect();
+        busy.sort();
+        assert_eq!(busy, vec![&1, &5]);
+        drop(other);
+        assert_eq!(wrap.busy_keys().collect::<Vec<_>>(), vec![&1]);
+        drop(loan);
+        assert_eq!(wrap.busy_keys().next(), None);
+        assert_eq!(*wrap.lend(&1).unwrap(), "One");
+        wrap.rollback();
+    }
+
+    #[test]
+    fn lend_errors() {
+        let mut lib = get_lib();
+        let mut wrap = LendingWrap::new(&mut lib);
+        wrap.remove(&0);
+        assert_eq!(wrap.lend_checked(&0).err(), Some(TxError::KeyRemoved));
+        assert_eq!(wrap.lend_checked(&9).err(), Some(TxError::KeyAbsent));
+        let loan = wrap.lend_checked(&1).unwrap();
+        assert_eq!(
+            wrap.lend_checked(&1).err(),
+            Some(TxError::OutstandingLoans(vec![1]))
+        );
+        let (wrap, err) = wrap.try_commit().unwrap_err();
+        assert_eq!(TxError::from(err), TxError::OutstandingLoans(vec![1]));
+        drop(loan);
+        wrap.rollback();
+        assert_eq!(lib.len(), 3);
+    }
+
+    #[test]
+    fn bulk_extend() {
+        let mut lib = get_lib();
+        let mut wrap = LendingWrap::new(&mut lib);
+        wrap.remove(&0);
+        wrap.extend(vec![
+            (0, "Nil".to_string()),
+            (1, "Uno".to_string()),
+            (5, "Five".to_string()),
+        ]);
+        wrap.commit();
+        assert_eq!(lib.len(), 4);
+        assert_eq!(*lib.lend(&0).unwrap(), "Nil");
+        assert_eq!(*lib.lend(&1).unwrap(), "Uno");
+        assert_eq!(*lib.lend(&5).unwrap(), "Five");
+    }
+
+    #[test]
+    fn view_equality() {
+        let mut lib = get_lib();
+        let mut wrap = LendingWrap::new(&mut lib);
+        let mut expected: HashMap<_, _> = get_lib().into_iter().collect();
+        assert!(wrap.eq_view(&expected));
+        wrap.insert(1, "Uno".to_string());
+        wrap.insert(5, "Five".to_string());
+        wrap.remove(&0);
+        assert!(!wrap.eq_view(&expected));
+        expected.insert(1, "Uno".to_string());
+        expected.insert(5, "Five".to_string());
+        expected.remove(&0);
+        assert!(wrap.eq_view(&expected));
+        {
+            let _loan = wrap.lend(&5).unwrap();
+            assert!(!wrap.eq_view(&expected));
+        }
+        assert!(wrap.eq_view(&expected));
+        wrap.rollback();
+    }
+
+    #[test]
+    fn nested_wraps() {
+        let mut lib = get_lib();
+        let mut outer = LendingWrap::new(&mut lib);
+        outer.insert(5, "Five".to_string());
+        {
+            let mut inner = LendingWrap::new(&mut outer);
+            inner.insert(6, "Six".to_string());
+            inner.get_mut(&1).unwrap().push_str("00");
+            inner.remove(&5);
+            inner.remove(&0);
+            assert!(inner.contains_key(&6));
+            assert!(!inner.contains_key(&0));
+            inner.commit();
+        }
+        assert!(outer.contains_key(&6));
+        assert!(!outer.contains_key(&5));
+        assert!(!outer.contains_key(&0));
+        assert_eq!(*outer.lend(&1).unwrap(), "One00");
+        outer.rollback();
+        assert_eq!(lib.len(), 3);
+        assert_eq!(*lib.lend(&0).unwrap(), "Zero");
+        assert_eq!(*lib.lend(&1).unwrap(), "One");
+        assert!(!lib.contains_key(&6));
+    }
+
+    #[test]
+    fn implicit_behaviors() {
+        let mut lib = get_lib();
+        let mut wrap = LendingWrap::new(&mut lib).into_implicit_rollback();
+        wrap.remove(&0);
+        wrap.insert(5, "Five".to_string());
+        drop(wrap);
+        assert!(lib.contains_key(&0));
+        assert!(!lib.contains_key(&5));
+
+        let mut wrap = LendingWrap::new(&mut lib).into_implicit_commit();
+        wrap.remove(&0);
+        wrap.insert(5, "Five".to_string());
+        wrap.get_mut(&1).unwrap().push_str("00");
+        drop(wrap);
+        assert!(!lib.contains_key(&0));
+        assert!(lib.contains_key(&5));
+        assert_eq!(*lib.lend(&1).unwrap(), "One00");
+
+        let mut wrap = LendingWrap::new(&mut lib).into_implicit_commit();
+        wrap.insert(6, "Six".to_string());
+        wrap.into_panicking().rollback();
+        assert!(!lib.contains_key(&6));
+    }
 }
@@ -1,6 +1,155 @@
-#![feature(vec_remove_item)]
+macro_rules! debug_event {
+    ($($arg:tt)*) => {
+        #[cfg(feature = "tracing")]
+        tracing::debug!($($arg)*);
+    };
+}
 
+// Implements `SpecDrop` for a wrapper's commit behaviours, along with the `Drop`
+// that dispatches to it. `PanicIfUnfinalised` is always implemented, and each
+// listed behaviour finishes the wrapper with the given method, under any extra
+// bounds that method needs. A leading `on_drop => method;` names a method that
+// `Drop` calls first whenever it finds the wrapper unfinalised.
+macro_rules! impl_spec_drop {
+    (@finish $head:tt) => {};
+    (
+        @finish [$wrap:ident<$($lt:lifetime,)? $($param:ident),*> where { $($bound:tt)* }]
+        $behavior:ident => $finish:ident $(where { $($extra:tt)* })?;
+        $($rest:tt)*
+    ) => {
+        impl<$($lt,)? $($param),*> $crate::hash_wrap::SpecDrop
+            for $wrap<$($lt,)? $($param,)* $crate::hash_wrap::commit_behavior::$behavior>
+        where
+            $($bound)* $(, $($extra)*)?
+        {
+            fn spec_drop(&mut self) {
+                self.$finish();
+            }
+        }
+
+        impl_spec_drop!(@finish [$wrap<$($lt,)? $($param),*> where { $($bound)* }] $($rest)*);
+    };
+    (
+        @drop [$($hook:ident)?]
+        $wrap:ident<$($lt:lifetime,)? $($param:ident),*> where { $($bound:tt)* }
+        $($rest:tt)*
+    ) => {
+        impl<$($lt,)? $($param),*> $crate::hash_wrap::SpecDrop
+            for $wrap<$($lt,)? $($param,)* $crate::hash_wrap::commit_behavior::PanicIfUnfinalised>
+        where
+            $($bound)*
+        {
+            fn spec_drop(&mut self) {
+                if !std::thread::panicking() {
+                    panic!("Error: Dropping wrapper without calling commit or rollback.")
+                }
+            }
+        }
+
+        impl<$($lt,)? $($param,)* B> Drop for $wrap<$($lt,)? $($param,)* B>
+        where
+            $wrap<$($lt,)? $($param,)* B>: $crate::hash_wrap::SpecDrop,
+            $($bound)*,
+            B: $crate::hash_wrap::commit_behavior::Behavior,
+        {
+            fn drop(&mut self) {
+                if !self.finalised {
+                    $(self.$hook();)?
+                    $crate::hash_wrap::SpecDrop::spec_drop(self);
+                }
+            }
+        }
+
+        impl_spec_drop!(@finish [$wrap<$($lt,)? $($param),*> where { $($bound)* }] $($rest)*);
+    };
+    (on_drop => $hook:ident; $($rest:tt)*) => {
+        impl_spec_drop!(@drop [$hook] $($rest)*);
+    };
+    ($($rest:tt)*) => {
+        impl_spec_drop!(@drop [] $($rest)*);
+    };
+}
+
+// Implements a wrapper's conversions between commit behaviours. `inner` is
+// always moved across; every other field bar `commit_behaviour` and
+// `finalised` must be listed, under `take` if it is moved out with
+// `mem::take`, under `copy` if it is `Copy`, or under `move` if it has no
+// `Default` and is read out like `inner`.
+macro_rules! impl_into_behavior {
+    (@into $head:tt) => {};
+    (
+        @into [$wrap:ident<$($lt:lifetime,)? $($param:ident),*> where { $($bound:tt)* }]
+        $into:ident => $behavior:ident;
+        $($rest:tt)*
+    ) => {
+        impl<$($lt,)? $($param,)* B> $wrap<$($lt,)? $($param,)* B>
+        where
+            $wrap<$($lt,)? $($param,)* B>: $crate::hash_wrap::SpecDrop,
+            $($bound)*,
+            B: $crate::hash_wrap::commit_behavior::Behavior,
+        {
+            pub fn $into(
+                self,
+            ) -> $wrap<$($lt,)? $($param,)* $crate::hash_wrap::commit_behavior::$behavior> {
+                self.into_behavior::<$crate::hash_wrap::commit_behavior::$behavior>()
+            }
+        }
+
+        impl_into_behavior!(@into [$wrap<$($lt,)? $($param),*> where { $($bound)* }] $($rest)*);
+    };
+    (
+        $wrap:ident<$($lt:lifetime,)? $($param:ident),*>
+        {
+            $(take: $($take:ident),+;)?
+            $(copy: $($copy:ident),+;)?
+            $(move: $($move:ident),+;)?
+        }
+        where { $($bound:tt)* }
+        $($rest:tt)*
+    ) => {
+        impl<$($lt,)? $($param,)* B> $wrap<$($lt,)? $($param,)* B>
+        where
+            $wrap<$($lt,)? $($param,)* B>: $crate::hash_wrap::SpecDrop,
+            $($bound)*,
+            B: $crate::hash_wrap::commit_behavior::Behavior,
+        {
+            fn into_behavior<B2>(self) -> $wrap<$($lt,)? $($param,)* B2>
+            where
+                $wrap<$($lt,)? $($param,)* B2>: $crate::hash_wrap::SpecDrop,
+                B2: $crate::hash_wrap::commit_behavior::Behavior,
+            {
+                #[allow(unused_mut)]
+                let mut this = std::mem::ManuallyDrop::new(self);
+                $wrap {
+                    // SAFETY: `this` is never dropped and `inner`, like any
+                    // `move` field, is never read from it again, so the
+                    // bitwise copy is the only live one. The borrow or handle
+                    // is moved rather than duplicated.
+                    inner: unsafe { std::ptr::read(&this.inner) },
+                    $($($move: unsafe { std::ptr::read(&this.$move) },)+)?
+                    $($($take: std::mem::take(&mut this.$take),)+)?
+                    $($($copy: this.$copy,)+)?
+                    commit_behaviour: std::marker::PhantomData,
+                    finalised: false,
+                }
+            }
+        }
+
+        impl_into_behavior!(@into [$wrap<$($lt,)? $($param),*> where { $($bound)* }] $($rest)*);
+    };
+}
+
+#[cfg(feature = "async")]
+pub mod async_wrap;
+pub mod btree_wrap;
 pub mod gen_wrap;
 pub mod hash_wrap;
 #[cfg(feature = "lending")]
 pub mod lending_wrap;
+pub mod ordered_wrap;
+pub mod prelude;
+pub mod set_wrap;
+pub mod shared_wrap;
+pub mod transaction;
+pub mod vec_deque_wrap;
+pub mod vec_wrap;
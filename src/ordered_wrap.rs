@@ -0,0 +1,263 @@
+use std::{
+    collections::{HashMap, HashSet},
+    hash::Hash,
+    marker::PhantomData,
+    mem,
+};
+
+use hashlike::HashLike;
+
+use crate::{
+    hash_wrap::{commit_behavior, SpecDrop},
+    transaction::Transaction,
+};
+
+// Like HashWrap, but every staged key is also recorded in `order` by when it
+// was last touched, and commit applies the keys in that order. A key touched
+// again leaves a gap at its old slot, found through `positions`, and the gaps
+// are squeezed out once they outnumber the keys.
+pub struct OrderedHashWrap<'a, K, V, T = HashMap<K, V>, B = commit_behavior::PanicIfUnfinalised>
+where
+    OrderedHashWrap<'a, K, V, T, B>: SpecDrop,
+    K: Eq + Hash,
+    T: HashLike<K, V>,
+    B: commit_behavior::Behavior,
+{
+    inner: &'a mut T,
+    added: HashMap<K, V>,
+    removed: HashSet<K>,
+    order: Vec<Option<K>>,
+    positions: HashMap<K, usize>,
+    commit_behaviour: PhantomData<B>,
+    finalised: bool,
+}
+
+impl<'a, K, V, T> OrderedHashWrap<'a, K, V, T>
+where
+    K: Eq + Hash,
+    T: HashLike<K, V>,
+{
+    pub fn new(map: &'a mut T) -> Self {
+        OrderedHashWrap {
+            inner: map,
+            added: HashMap::new(),
+            removed: HashSet::new(),
+            order: Vec::new(),
+            positions: HashMap::new(),
+            commit_behaviour: PhantomData,
+            finalised: false,
+        }
+    }
+}
+
+impl<'a, K, V, T, B> OrderedHashWrap<'a, K, V, T, B>
+where
+    OrderedHashWrap<'a, K, V, T, B>: SpecDrop,
+    K: Eq + Hash + Clone,
+    T: HashLike<K, V>,
+    V: Clone,
+    B: commit_behavior::Behavior,
+{
+    fn _commit(&mut self) {
+        self.positions.clear();
+        for k in mem::take(&mut self.order).into_iter().flatten() {
+            match self.added.remove(&k) {
+                Some(v) => {
+                    self.inner.insert(k, v);
+                }
+                None => {
+                    self.inner.remove(&k);
+                }
+            }
+        }
+        self.removed.clear();
+        self.finalised = true;
+    }
+
+    pub fn commit(mut self) {
+        self._commit()
+    }
+
+    fn _rollback(&mut self) {
+        self.finalised = true;
+    }
+
+    pub fn rollback(mut self) {
+        self._rollback()
+    }
+
+    fn touch(&mut self, k: &K) {
+        if let Some(pos) = self.positions.insert(k.clone(), self.order.len()) {
+            self.order[pos] = None;
+        }
+        self.order.push(Some(k.clone()));
+        if self.order.len() > 2 * self.positions.len() {
+            self.order.retain(Option::is_some);
+            for (pos, k) in self.order.iter().flatten().enumerate() {
+                self.positions.insert(k.clone(), pos);
+            }
+        }
+    }
+
+    pub fn staged_order(&self) -> impl Iterator<Item = &K> {
+        self.order.iter().flatten()
+    }
+
+    pub fn contains_key(&self, k: &K) -> bool {
+        self.added.contains_key(k) || (!self.removed.contains(k) && self.inner.contains_key(k))
+    }
+
+    pub fn get(&self, k: &K) -> Option<&V> {
+        if self.added.contains_key(k) {
+            self.added.get(k)
+        } else if self.removed.contains(k) {
+            None
+        } else {
+            self.inner.get(k)
+        }
+    }
+
+    pub fn insert(&mut self, k: K, v: V) -> Option<V> {
+        self.touch(&k);
+        if self.added.contains_key(&k) || self.removed.remove(&k) {
+            self.added.insert(k, v)
+        } else {
+            let ret = self.inner.get(&k).cloned();
+            self.added.insert(k, v);
+            ret
+        }
+    }
+
+    pub fn remove(&mut self, k: &K) -> Option<V> {
+        self.touch(k);
+        if self.added.contains_key(k) {
+            self.removed.insert(k.clone());
+            self.added.remove(k)
+        } else if self.removed.contains(k) {
+            None
+        } else {
+            self.removed.insert(k.clone());
+            self.inner.get(k).cloned()
+        }
+    }
+}
+
+impl<'a, K, V, T, B> Transaction for OrderedHashWrap<'a, K, V, T, B>
+where
+    OrderedHashWrap<'a, K, V, T, B>: SpecDrop,
+    K: Eq + Hash + Clone,
+    T: HashLike<K, V>,
+    V: Clone,
+    B: commit_behavior::Behavior,
+{
+    fn commit(self) {
+        OrderedHashWrap::commit(self)
+    }
+
+    fn rollback(self) {
+        OrderedHashWrap::rollback(self)
+    }
+}
+
+impl_into_behavior! {
+    OrderedHashWrap<'a, K, V, T> { take: added, removed, order, positions; }
+    where { K: Eq + Hash + Clone, V: Clone, T: HashLike<K, V> }
+    into_implicit_commit => ImplicitCommit;
+    into_implicit_rollback => ImplicitRollback;
+    into_panicking => PanicIfUnfinalised;
+}
+
+impl_spec_drop! {
+    OrderedHashWrap<'a, K, V, T> where { K: Eq + Hash, T: HashLike<K, V> }
+    ImplicitCommit => _commit where { K: Clone, V: Clone };
+    ImplicitRollback => _rollback where { K: Clone, V: Clone };
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[derive(Debug, PartialEq)]
+    enum Op {
+        Insert(i32),
+        Remove(i32),
+    }
+
+    #[derive(Default)]
+    struct Logged {
+        map: HashMap<i32, String>,
+        log: Vec<Op>,
+    }
+
+    impl HashLike<i32, String> for Logged {
+        fn get(&self, k: &i32) -> Option<&String> {
+            self.map.get(k)
+        }
+
+        fn insert(&mut self, k: i32, v: String) -> Option<String> {
+            self.log.push(Op::Insert(k));
+            self.map.insert(k, v)
+        }
+
+        fn remove(&mut self, k: &i32) -> Option<String> {
+            self.log.push(Op::Remove(*k));
+            self.map.remove(k)
+        }
+
+        fn contains_key(&self, k: &i32) -> bool {
+            self.map.contains_key(k)
+        }
+    }
+
+    #[test]
+    fn commit_order() {
+        let mut store = Logged::default();
+        store.map.insert(0, "Zero".to_string());
+        let mut wrap = OrderedHashWrap::new(&mut store);
+        wrap.insert(3, "Three".to_string());
+        wrap.remove(&0);
+        wrap.insert(1, "One".to_string());
+        wrap.insert(2, "Two".to_string());
+        wrap.remove(&1);
+        wrap.insert(3, "Drei".to_string());
+        assert_eq!(
+            wrap.staged_order().collect::<Vec<_>>(),
+            vec![&0, &2, &1, &3]
+        );
+        assert_eq!(wrap.get(&3), Some(&"Drei".to_string()));
+        assert!(!wrap.contains_key(&1));
+        wrap.commit();
+        assert_eq!(
+            store.log,
+            vec![Op::Remove(0), Op::Insert(2), Op::Remove(1), Op::Insert(3)]
+        );
+        assert_eq!(store.map.len(), 2);
+        assert_eq!(store.map[&3], "Drei");
+    }
+
+    #[test]
+    fn retouching_keeps_order() {
+        let mut store = Logged::default();
+        let mut wrap = OrderedHashWrap::new(&mut store);
+        for i in 0..10 {
+            wrap.insert(i % 3, i.to_string());
+        }
+        wrap.remove(&1);
+        assert_eq!(wrap.staged_order().collect::<Vec<_>>(), vec![&2, &0, &1]);
+        wrap.commit();
+        assert_eq!(store.log, vec![Op::Insert(2), Op::Insert(0), Op::Remove(1)]);
+        assert_eq!(store.map[&0], "9");
+    }
+
+    #[test]
+    fn rollback_applies_nothing() {
+        let mut store = Logged::default();
+        let mut wrap = OrderedHashWrap::new(&mut store);
+        wrap.insert(1, "One".to_string());
+        wrap.rollback();
+        let mut wrap = OrderedHashWrap::new(&mut store).into_implicit_rollback();
+        wrap.insert(2, "Two".to_string());
+        drop(wrap);
+        assert!(store.log.is_empty());
+    }
+}
@@ -0,0 +1,54 @@
+#[cfg(feature = "async")]
+pub use crate::async_wrap::{AsyncHashWrap, HashLikeAsync};
+#[cfg(feature = "lending")]
+pub use crate::lending_wrap::{LendingLike, LendingWrap, OutstandingLoansError, StagedLoan};
+#[cfg(feature = "async")]
+pub use crate::transaction::AsyncTransaction;
+pub use crate::{
+    btree_wrap::BTreeMapWrap,
+    gen_wrap::{CopyWrap, GenericWrap},
+    hash_wrap::{
+        commit_behavior::{ImplicitCommit, ImplicitRollback, PanicIfUnfinalised},
+        Changeset, ConflictError, Generation, HashWrap, KeyError, MergeStrategy, SavepointError,
+        TxError,
+    },
+    ordered_wrap::OrderedHashWrap,
+    set_wrap::SetWrap,
+    shared_wrap::{Isolation, SharedHashWrap},
+    transaction::{DynTransaction, Transaction, TransactionManager, TwoPhase},
+    vec_deque_wrap::VecDequeWrap,
+    vec_wrap::VecWrap,
+};
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use crate::prelude::*;
+
+    fn finish<T: Transaction>(t: T, ok: bool) -> Result<(), TxError<i32>> {
+        if ok {
+            t.commit();
+            Ok(())
+        } else {
+            t.rollback();
+            Err(TxError::Unfinalised)
+        }
+    }
+
+    #[test]
+    fn prelude_usage() {
+        let mut map: HashMap<i32, String> = HashMap::new();
+        let mut val = 1;
+        let mut wrap = HashWrap::new(&mut map);
+        wrap.insert(1, "One".to_string());
+        let mut gen = GenericWrap::new(&mut val);
+        *gen = 2;
+        assert_eq!(finish(wrap, true), Ok(()));
+        assert_eq!(finish(gen, false), Err(TxError::Unfinalised));
+        let wrap: HashWrap<_, _, _, ImplicitRollback> = HashWrap::new(&mut map);
+        drop(wrap);
+        assert_eq!(map[&1], "One");
+        assert_eq!(val, 1);
+    }
+}
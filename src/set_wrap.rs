@@ -0,0 +1,187 @@
+use std::{collections::HashSet, hash::Hash, marker::PhantomData};
+
+use crate::{
+    hash_wrap::{commit_behavior, SpecDrop},
+    transaction::Transaction,
+};
+
+pub struct SetWrap<'a, T, B = commit_behavior::PanicIfUnfinalised>
+where
+    SetWrap<'a, T, B>: SpecDrop,
+    T: Eq + Hash,
+    B: commit_behavior::Behavior,
+{
+    inner: &'a mut HashSet<T>,
+    added: HashSet<T>,
+    removed: HashSet<T>,
+    commit_behaviour: PhantomData<B>,
+    finalised: bool,
+}
+
+impl<'a, T> SetWrap<'a, T>
+where
+    T: Eq + Hash,
+{
+    pub fn new(set: &'a mut HashSet<T>) -> Self {
+        SetWrap {
+            inner: set,
+            added: HashSet::new(),
+            removed: HashSet::new(),
+            commit_behaviour: PhantomData,
+            finalised: false,
+        }
+    }
+}
+
+// Values are only staged in `added` if the backing set lacks them, and only in
+// `removed` if it has them, so the two never overlap.
+impl<'a, T, B> SetWrap<'a, T, B>
+where
+    SetWrap<'a, T, B>: SpecDrop,
+    T: Eq + Hash + Clone,
+    B: commit_behavior::Behavior,
+{
+    fn _commit(&mut self) {
+        for v in self.removed.drain() {
+            self.inner.remove(&v);
+        }
+        self.inner.extend(self.added.drain());
+        self.finalised = true;
+    }
+
+    pub fn commit(mut self) {
+        self._commit()
+    }
+
+    fn _rollback(&mut self) {
+        self.finalised = true;
+    }
+
+    pub fn rollback(mut self) {
+        self._rollback()
+    }
+
+    pub fn contains(&self, v: &T) -> bool {
+        self.added.contains(v) || (self.inner.contains(v) && !self.removed.contains(v))
+    }
+
+    pub fn insert(&mut self, v: T) -> bool {
+        if self.contains(&v) {
+            false
+        } else {
+            if !self.removed.remove(&v) {
+                self.added.insert(v);
+            }
+            true
+        }
+    }
+
+    pub fn remove(&mut self, v: &T) -> bool {
+        if self.added.remove(v) {
+            true
+        } else {
+            if self.inner.contains(v) && !self.removed.contains(v) {
+                self.removed.insert(v.clone());
+                true
+            } else {
+                false
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.len() - self.removed.len() + self.added.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        let removed = &self.removed;
+        self.inner
+            .iter()
+            .filter(move |v| !removed.contains(v))
+            .chain(self.added.iter())
+    }
+}
+
+impl<'a, T, B> Transaction for SetWrap<'a, T, B>
+where
+    SetWrap<'a, T, B>: SpecDrop,
+    T: Eq + Hash + Clone,
+    B: commit_behavior::Behavior,
+{
+    fn commit(self) {
+        SetWrap::commit(self)
+    }
+
+    fn rollback(self) {
+        SetWrap::rollback(self)
+    }
+}
+
+impl_into_behavior! {
+    SetWrap<'a, T> { take: added, removed; }
+    where { T: Eq + Hash + Clone }
+    into_implicit_commit => ImplicitCommit;
+    into_implicit_rollback => ImplicitRollback;
+    into_panicking => PanicIfUnfinalised;
+}
+
+impl_spec_drop! {
+    SetWrap<'a, T> where { T: Eq + Hash }
+    ImplicitCommit => _commit where { T: Clone };
+    ImplicitRollback => _rollback where { T: Clone };
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn get_set() -> HashSet<i32> {
+        vec![0, 1, 2].into_iter().collect()
+    }
+
+    #[test]
+    fn staged_edits() {
+        let mut set = get_set();
+        let mut wrap = SetWrap::new(&mut set);
+        assert!(wrap.insert(5));
+        assert!(!wrap.insert(5));
+        assert!(!wrap.insert(1));
+        assert!(wrap.remove(&0));
+        assert!(!wrap.remove(&0));
+        assert!(wrap.insert(6));
+        assert!(wrap.remove(&6));
+        assert!(!wrap.contains(&6));
+        assert!(wrap.insert(0));
+        assert!(wrap.remove(&0));
+        assert_eq!(wrap.len(), 3);
+        let mut vals: Vec<_> = wrap.iter().cloned().collect();
+        vals.sort();
+        assert_eq!(vals, vec![1, 2, 5]);
+        wrap.commit();
+        assert_eq!(set, vec![1, 2, 5].into_iter().collect());
+    }
+
+    #[test]
+    fn rollback_and_implicit() {
+        let mut set = get_set();
+        let mut wrap = SetWrap::new(&mut set);
+        wrap.insert(5);
+        wrap.remove(&1);
+        wrap.rollback();
+        assert_eq!(set, get_set());
+
+        let mut wrap = SetWrap::new(&mut set);
+        wrap.insert(5);
+        drop(wrap.into_implicit_rollback());
+        assert_eq!(set, get_set());
+
+        let mut wrap = SetWrap::new(&mut set);
+        wrap.remove(&2);
+        drop(wrap.into_implicit_commit());
+        assert_eq!(set, vec![0, 1].into_iter().collect());
+    }
+}
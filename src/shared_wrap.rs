@@ -0,0 +1,408 @@
+use std::{
+    collections::{HashMap, HashSet},
+    hash::Hash,
+    marker::PhantomData,
+    sync::{Arc, Mutex, MutexGuard},
+    thread,
+};
+
+use hashlike::HashLike;
+
+use crate::{
+    hash_wrap::{apply_staging, commit_behavior, ConflictError, Generation, SpecDrop},
+    transaction::Transaction,
+};
+
+// What reads of unstaged keys see while other threads commit to the store.
+// `ReadCommitted` reads the live store, and `Snapshot` reads a copy of the
+// store taken when the wrap was opened. Another wrap's staging is never
+// visible, so there is no weaker level.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Isolation {
+    #[default]
+    ReadCommitted,
+    Snapshot,
+}
+
+type GenerationFn<T> = fn(&T) -> u64;
+
+// Edits are staged locally without holding the lock, which is only taken for
+// reads of unstaged keys and to apply the staging area at commit.
+pub struct SharedHashWrap<K, V, T = HashMap<K, V>, B = commit_behavior::PanicIfUnfinalised>
+where
+    SharedHashWrap<K, V, T, B>: SpecDrop,
+    K: Eq + Hash,
+    T: HashLike<K, V>,
+    B: commit_behavior::Behavior,
+{
+    inner: Arc<Mutex<T>>,
+    added: HashMap<K, V>,
+    removed: HashSet<K>,
+    generation: Option<(u64, GenerationFn<T>)>,
+    isolation: Isolation,
+    snapshot: Option<T>,
+    commit_behaviour: PhantomData<B>,
+    finalised: bool,
+}
+
+impl<K, V, T> SharedHashWrap<K, V, T>
+where
+    K: Eq + Hash,
+    T: HashLike<K, V>,
+{
+    pub fn new(store: Arc<Mutex<T>>) -> Self {
+        SharedHashWrap {
+            inner: store,
+            added: HashMap::new(),
+            removed: HashSet::new(),
+            generation: None,
+            isolation: Isolation::ReadCommitted,
+            snapshot: None,
+            commit_behaviour: PhantomData,
+            finalised: false,
+        }
+    }
+
+    pub fn with_isolation(store: Arc<Mutex<T>>, isolation: Isolation) -> Self
+    where
+        T: Clone,
+    {
+        let snapshot = match isolation {
+            Isolation::Snapshot => Some(lock(&store).clone()),
+            Isolation::ReadCommitted => None,
+        };
+        let mut wrap = SharedHashWrap::new(store);
+        wrap.isolation = isolation;
+        wrap.snapshot = snapshot;
+        wrap
+    }
+}
+
+impl<K, V, T> SharedHashWrap<K, V, T>
+where
+    K: Eq + Hash,
+    T: HashLike<K, V> + Generation,
+{
+    pub fn new_checked(store: Arc<Mutex<T>>) -> Self {
+        let generation = lock(&store).generation();
+        let mut wrap = SharedHashWrap::new(store);
+        wrap.generation = Some((generation, T::generation));
+        wrap
+    }
+}
+
+fn lock<T>(store: &Mutex<T>) -> MutexGuard<'_, T> {
+    store
+        .lock()
+        .expect("Error: The shared store's lock was poisoned.")
+}
+
+impl<K, V, T, B> SharedHashWrap<K, V, T, B>
+where
+    SharedHashWrap<K, V, T, B>: SpecDrop,
+    K: Eq + Hash,
+    T: HashLike<K, V>,
+    B: commit_behavior::Behavior,
+{
+    // A checked wrap has no way to report a conflict from here, so a stale one
+    // is refused outright and has to go through `try_commit` instead. The lock
+    // is released before panicking so that it isn't poisoned.
+    fn _commit(&mut self) {
+        let mut store = lock(&self.inner);
+        let stale = matches!(self.generation, Some((g, current)) if g != current(&store));
+        if !stale {
+            apply_staging(&mut *store, &mut self.added, &self.removed);
+        }
+        drop(store);
+        self.finalised = true;
+        if stale && !thread::panicking() {
+            panic!(
+                "Error: Committing a checked wrapper whose store has changed since it was opened."
+            )
+        }
+    }
+
+    pub fn commit(mut self) {
+        self._commit()
+    }
+
+    fn _rollback(&mut self) {
+        self.finalised = true;
+    }
+
+    pub fn rollback(mut self) {
+        self._rollback()
+    }
+
+    pub fn isolation(&self) -> Isolation {
+        self.isolation
+    }
+
+    pub fn contains_key(&self, k: &K) -> bool {
+        self.added.contains_key(k)
+            || (!self.removed.contains(k)
+                && match &self.snapshot {
+                    Some(snapshot) => snapshot.contains_key(k),
+                    None => lock(&self.inner).contains_key(k),
+                })
+    }
+
+    // Values behind the lock can't be borrowed past it, so reads are cloned.
+    pub fn get(&self, k: &K) -> Option<V>
+    where
+        V: Clone,
+    {
+        if self.added.contains_key(k) {
+            self.added.get(k).cloned()
+        } else if self.removed.contains(k) {
+            None
+        } else {
+            self.get_unstaged(k)
+        }
+    }
+
+    fn get_unstaged(&self, k: &K) -> Option<V>
+    where
+        V: Clone,
+    {
+        match &self.snapshot {
+            Some(snapshot) => snapshot.get(k).cloned(),
+            None => lock(&self.inner).get(k).cloned(),
+        }
+    }
+
+    pub fn insert(&mut self, k: K, v: V) -> Option<V>
+    where
+        V: Clone,
+    {
+        if self.added.contains_key(&k) || self.removed.remove(&k) {
+            self.added.insert(k, v)
+        } else {
+            let ret = self.get_unstaged(&k);
+            self.added.insert(k, v);
+            ret
+        }
+    }
+
+    pub fn remove(&mut self, k: &K) -> Option<V>
+    where
+        K: Clone,
+        V: Clone,
+    {
+        if self.added.contains_key(k) {
+            self.removed.insert(k.clone());
+            self.added.remove(k)
+        } else if self.removed.contains(k) {
+            None
+        } else {
+            self.removed.insert(k.clone());
+            self.get_unstaged(k)
+        }
+    }
+}
+
+impl<K, V, T, B> SharedHashWrap<K, V, T, B>
+where
+    SharedHashWrap<K, V, T, B>: SpecDrop,
+    K: Eq + Hash + Clone,
+    T: HashLike<K, V> + Generation,
+    B: commit_behavior::Behavior,
+{
+    // The generation is compared and the staging applied under a single lock,
+    // so no other commit can slip in between the two.
+    pub fn try_commit(mut self) -> Result<(), ConflictError<K>> {
+        let inner = self.inner.clone();
+        let mut store = lock(&inner);
+        match self.generation {
+            Some((g, _)) if g != store.generation() => {
+                let mut keys: Vec<K> = self.added.drain().map(|(k, _)| k).collect();
+                keys.extend(self.removed.drain());
+                self._rollback();
+                Err(ConflictError { keys })
+            }
+            _ => {
+                apply_staging(&mut *store, &mut self.added, &self.removed);
+                self.finalised = true;
+                Ok(())
+            }
+        }
+    }
+}
+
+impl<K, V, T, B> Transaction for SharedHashWrap<K, V, T, B>
+where
+    SharedHashWrap<K, V, T, B>: SpecDrop,
+    K: Eq + Hash,
+    T: HashLike<K, V>,
+    B: commit_behavior::Behavior,
+{
+    fn commit(self) {
+        SharedHashWrap::commit(self)
+    }
+
+    fn rollback(self) {
+        SharedHashWrap::rollback(self)
+    }
+}
+
+impl_into_behavior! {
+    SharedHashWrap<K, V, T> { take: added, removed, snapshot; copy: generation, isolation; }
+    where { K: Eq + Hash, T: HashLike<K, V> }
+    into_implicit_commit => ImplicitCommit;
+    into_implicit_rollback => ImplicitRollback;
+    into_panicking => PanicIfUnfinalised;
+}
+
+impl_spec_drop! {
+    SharedHashWrap<K, V, T> where { K: Eq + Hash, T: HashLike<K, V> }
+    ImplicitCommit => _commit;
+    ImplicitRollback => _rollback;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct Versioned {
+        map: HashMap<i32, u32>,
+        generation: u64,
+    }
+
+    impl HashLike<i32, u32> for Versioned {
+        fn get(&self, k: &i32) -> Option<&u32> {
+            self.map.get(k)
+        }
+
+        fn insert(&mut self, k: i32, v: u32) -> Option<u32> {
+            self.generation += 1;
+            self.map.insert(k, v)
+        }
+
+        fn remove(&mut self, k: &i32) -> Option<u32> {
+            self.generation += 1;
+            self.map.remove(k)
+        }
+
+        fn contains_key(&self, k: &i32) -> bool {
+            self.map.contains_key(k)
+        }
+    }
+
+    impl Generation for Versioned {
+        fn generation(&self) -> u64 {
+            self.generation
+        }
+    }
+
+    #[test]
+    fn staged_locally() {
+        let store = Arc::new(Mutex::new(HashMap::new()));
+        lock(&store).insert(0, 0);
+        let mut wrap = SharedHashWrap::new(store.clone());
+        assert_eq!(wrap.insert(1, 1), None);
+        assert_eq!(wrap.insert(1, 2), Some(1));
+        assert_eq!(wrap.remove(&0), Some(0));
+        assert_eq!(wrap.remove(&0), None);
+        assert_eq!(wrap.get(&1), Some(2));
+        assert!(!wrap.contains_key(&0));
+        assert!(lock(&store).contains_key(&0));
+        wrap.commit();
+        assert_eq!(*lock(&store), vec![(1, 2)].into_iter().collect());
+
+        let mut wrap = SharedHashWrap::new(store.clone()).into_implicit_rollback();
+        wrap.insert(2, 2);
+        drop(wrap);
+        assert!(!lock(&store).contains_key(&2));
+    }
+
+    #[test]
+    fn conversion_moves_handle() {
+        let store = Arc::new(Mutex::new(HashMap::new()));
+        let mut wrap = SharedHashWrap::new(store.clone());
+        wrap.insert(1, 1);
+        let wrap = wrap.into_implicit_rollback().into_implicit_commit();
+        assert_eq!(Arc::strong_count(&store), 2);
+        drop(wrap);
+        assert_eq!(Arc::strong_count(&store), 1);
+        assert_eq!(lock(&store).get(&1), Some(&1));
+    }
+
+    #[test]
+    fn snapshot_reads() {
+        let store = Arc::new(Mutex::new(HashMap::new()));
+        lock(&store).insert(0, 0);
+        let snapshot = SharedHashWrap::with_isolation(store.clone(), Isolation::Snapshot);
+        let live = SharedHashWrap::with_isolation(store.clone(), Isolation::ReadCommitted);
+        lock(&store).insert(0, 10);
+        lock(&store).insert(1, 1);
+        assert_eq!(snapshot.isolation(), Isolation::Snapshot);
+        assert_eq!(snapshot.get(&0), Some(0));
+        assert!(!snapshot.contains_key(&1));
+        assert_eq!(live.get(&0), Some(10));
+        assert!(live.contains_key(&1));
+        snapshot.rollback();
+        live.rollback();
+    }
+
+    #[test]
+    #[should_panic(expected = "has changed since it was opened")]
+    fn stale_checked_commit() {
+        let store = Arc::new(Mutex::new(Versioned {
+            map: HashMap::new(),
+            generation: 0,
+        }));
+        let mut wrap = SharedHashWrap::new_checked(store.clone());
+        wrap.insert(0, 0);
+        lock(&store).insert(1, 1);
+        wrap.commit();
+    }
+
+    #[test]
+    fn stale_implicit_commit_applies_nothing() {
+        let store = Arc::new(Mutex::new(Versioned {
+            map: HashMap::new(),
+            generation: 0,
+        }));
+        let mut wrap = SharedHashWrap::new_checked(store.clone()).into_implicit_commit();
+        wrap.insert(0, 0);
+        lock(&store).insert(1, 1);
+        let dropped = thread::spawn(move || drop(wrap)).join();
+        assert!(dropped.is_err());
+        assert!(!lock(&store).map.contains_key(&0));
+
+        let mut wrap = SharedHashWrap::new_checked(store.clone()).into_implicit_commit();
+        wrap.insert(2, 2);
+        drop(wrap);
+        assert_eq!(lock(&store).map[&2], 2);
+    }
+
+    #[test]
+    fn no_lost_updates() {
+        const ROUNDS: u32 = 200;
+        let store = Arc::new(Mutex::new(Versioned {
+            map: vec![(0, 0)].into_iter().collect(),
+            generation: 0,
+        }));
+        let threads: Vec<_> = (0..2)
+            .map(|_| {
+                let store = store.clone();
+                thread::spawn(move || {
+                    for _ in 0..ROUNDS {
+                        loop {
+                            let mut wrap = SharedHashWrap::new_checked(store.clone());
+                            let count = wrap.get(&0).unwrap();
+                            wrap.insert(0, count + 1);
+                            if wrap.try_commit().is_ok() {
+                                break;
+                            }
+                        }
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert_eq!(lock(&store).map[&0], 2 * ROUNDS);
+    }
+}
@@ -0,0 +1,342 @@
+pub trait Transaction {
+    fn commit(self);
+    fn rollback(self);
+}
+
+// `Transaction` consumes the wrap by value, so it can't be used as a trait
+// object. Every transaction is also a `DynTransaction`, which finalises
+// through a box instead.
+pub trait DynTransaction {
+    fn commit_boxed(self: Box<Self>);
+    fn rollback_boxed(self: Box<Self>);
+}
+
+impl<T> DynTransaction for T
+where
+    T: Transaction,
+{
+    fn commit_boxed(self: Box<Self>) {
+        (*self).commit()
+    }
+
+    fn rollback_boxed(self: Box<Self>) {
+        (*self).rollback()
+    }
+}
+
+impl Transaction for Box<dyn DynTransaction + '_> {
+    fn commit(self) {
+        self.commit_boxed()
+    }
+
+    fn rollback(self) {
+        self.rollback_boxed()
+    }
+}
+
+pub fn finalise<T>(t: T, commit: bool)
+where
+    T: Transaction,
+{
+    if commit {
+        t.commit()
+    } else {
+        t.rollback()
+    }
+}
+
+// Applying to an async store has to await, so wrappers over one finalise
+// through this instead of `Transaction`.
+#[cfg(feature = "async")]
+#[allow(async_fn_in_trait)]
+pub trait AsyncTransaction {
+    async fn commit(self);
+    async fn rollback(self);
+}
+
+// Runs `$body` with `$w` bound to `&mut` the wrap. The wrap is committed if the
+// body evaluates to `Ok`, and rolled back if it evaluates to `Err` or returns
+// early through `?`. The body's result is passed through.
+#[macro_export]
+macro_rules! transaction {
+    ($wrap:expr, |$w:ident| $body:block) => {{
+        let mut wrap = $wrap;
+        let res = $crate::transaction::__scope(&mut wrap, |$w| $body);
+        match res {
+            Ok(v) => {
+                $crate::transaction::Transaction::commit(wrap);
+                Ok(v)
+            }
+            Err(e) => {
+                $crate::transaction::Transaction::rollback(wrap);
+                Err(e)
+            }
+        }
+    }};
+}
+
+// Passing the wrap through a function fixes the closure's argument type before
+// the body is checked.
+#[doc(hidden)]
+pub fn __scope<W, R, F>(w: &mut W, f: F) -> R
+where
+    F: FnOnce(&mut W) -> R,
+{
+    f(w)
+}
+
+// A participant in a TransactionManager commit. `prepare` checks that `apply`
+// will succeed without changing anything, so a failure can still abort every
+// participant.
+pub trait TwoPhase {
+    fn prepare(&mut self) -> bool;
+    fn apply(self: Box<Self>);
+    fn abort(self: Box<Self>);
+}
+
+struct Unchecked<T>(T);
+
+impl<T> TwoPhase for Unchecked<T>
+where
+    T: Transaction,
+{
+    fn prepare(&mut self) -> bool {
+        true
+    }
+
+    fn apply(self: Box<Self>) {
+        self.0.commit()
+    }
+
+    fn abort(self: Box<Self>) {
+        self.0.rollback()
+    }
+}
+
+#[derive(Default)]
+pub struct TransactionManager<'a> {
+    participants: Vec<Box<dyn TwoPhase + 'a>>,
+}
+
+impl<'a> TransactionManager<'a> {
+    pub fn new() -> Self {
+        TransactionManager {
+            participants: Vec::new(),
+        }
+    }
+
+    pub fn add<T>(&mut self, t: T)
+    where
+        T: TwoPhase + 'a,
+    {
+        self.participants.push(Box::new(t));
+    }
+
+    pub fn add_unchecked<T>(&mut self, t: T)
+    where
+        T: Transaction + 'a,
+    {
+        self.participants.push(Box::new(Unchecked(t)));
+    }
+
+    pub fn len(&self) -> usize {
+        self.participants.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.participants.is_empty()
+    }
+
+    // If a participant fails to prepare, nothing is applied or aborted. The
+    // manager is handed back with the index of the first failure, so the
+    // caller can clear the cause and retry, or roll everything back.
+    pub fn commit(mut self) -> Result<(), (usize, Self)> {
+        match self.participants.iter_mut().position(|p| !p.prepare()) {
+            Some(idx) => Err((idx, self)),
+            None => {
+                for p in self.participants {
+                    p.apply();
+                }
+                Ok(())
+            }
+        }
+    }
+
+    pub fn rollback(self) {
+        for p in self.participants {
+            p.abort();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{cell::Cell, collections::HashMap, rc::Rc};
+
+    use hashlike::HashLike;
+
+    use super::*;
+    use crate::{
+        gen_wrap::GenericWrap,
+        hash_wrap::{Generation, HashWrap},
+        vec_wrap::VecWrap,
+    };
+
+    struct Shared {
+        map: HashMap<i32, String>,
+        generation: Rc<Cell<u64>>,
+    }
+
+    impl HashLike<i32, String> for Shared {
+        fn get(&self, k: &i32) -> Option<&String> {
+            self.map.get(k)
+        }
+
+        fn insert(&mut self, k: i32, v: String) -> Option<String> {
+            self.generation.set(self.generation.get() + 1);
+            self.map.insert(k, v)
+        }
+
+        fn remove(&mut self, k: &i32) -> Option<String> {
+            self.generation.set(self.generation.get() + 1);
+            self.map.remove(k)
+        }
+
+        fn contains_key(&self, k: &i32) -> bool {
+            self.map.contains_key(k)
+        }
+    }
+
+    impl Generation for Shared {
+        fn generation(&self) -> u64 {
+            self.generation.get()
+        }
+    }
+
+    #[test]
+    fn generic_finalise() {
+        let mut map: HashMap<i32, String> = HashMap::new();
+        let mut val = 1;
+        let mut wrap = HashWrap::new(&mut map);
+        wrap.insert(5, "Five".to_string());
+        finalise(wrap, true);
+        let mut wrap = GenericWrap::new(&mut val);
+        *wrap = 2;
+        finalise(wrap, false);
+        assert_eq!(map[&5], "Five");
+        assert_eq!(val, 1);
+    }
+
+    #[test]
+    fn boxed_transactions() {
+        let mut map: HashMap<i32, String> = HashMap::new();
+        let mut vec = vec![1, 2, 3];
+        let mut val = 1;
+        {
+            let mut hash = HashWrap::new(&mut map);
+            hash.insert(5, "Five".to_string());
+            let mut vw = VecWrap::new(&mut vec);
+            vw.push(4);
+            let mut gen = GenericWrap::new(&mut val);
+            *gen = 2;
+            let txs: Vec<Box<dyn DynTransaction>> =
+                vec![Box::new(hash), Box::new(vw), Box::new(gen)];
+            for (i, tx) in txs.into_iter().enumerate() {
+                finalise(tx, i != 1);
+            }
+        }
+        assert_eq!(map[&5], "Five");
+        assert_eq!(vec, vec![1, 2, 3]);
+        assert_eq!(val, 2);
+    }
+
+    #[test]
+    fn scoped_transaction() {
+        let mut map: HashMap<i32, String> = HashMap::new();
+        let res: Result<usize, ()> = crate::transaction!(HashWrap::new(&mut map), |w| {
+            w.insert(1, "One".to_string());
+            w.insert(2, "Two".to_string());
+            Ok(2)
+        });
+        assert_eq!(res, Ok(2));
+        assert_eq!(map.len(), 2);
+
+        let parse = |s: &str| s.parse::<i32>().map_err(|_| s.to_string());
+        let res: Result<(), String> = crate::transaction!(HashWrap::new(&mut map), |w| {
+            w.remove(&1);
+            let k = parse("three")?;
+            w.insert(k, "Three".to_string());
+            Ok(())
+        });
+        assert_eq!(res, Err("three".to_string()));
+        assert_eq!(map[&1], "One");
+        assert_eq!(map.len(), 2);
+    }
+
+    #[test]
+    fn all_or_nothing() {
+        let generation = Rc::new(Cell::new(0));
+        let mut store = Shared {
+            map: HashMap::new(),
+            generation: generation.clone(),
+        };
+        let mut map: HashMap<i32, String> = HashMap::new();
+
+        let mut checked: HashWrap<_, _, _> = HashWrap::new_checked(&mut store);
+        checked.insert(1, "One".to_string());
+        let mut plain = HashWrap::new(&mut map);
+        plain.insert(2, "Two".to_string());
+        let mut manager = TransactionManager::new();
+        manager.add_unchecked(plain);
+        manager.add(checked);
+        generation.set(7);
+        match manager.commit() {
+            Err((idx, manager)) => {
+                assert_eq!(idx, 1);
+                manager.rollback();
+            }
+            Ok(()) => panic!("commit over a stale generation succeeded"),
+        }
+        assert!(map.is_empty());
+        assert!(store.map.is_empty());
+
+        let mut checked: HashWrap<_, _, _> = HashWrap::new_checked(&mut store);
+        checked.insert(1, "One".to_string());
+        let mut plain = HashWrap::new(&mut map);
+        plain.insert(2, "Two".to_string());
+        let mut manager = TransactionManager::new();
+        manager.add_unchecked(plain);
+        manager.add(checked);
+        assert_eq!(manager.len(), 2);
+        assert!(manager.commit().is_ok());
+        assert_eq!(map[&2], "Two");
+        assert_eq!(store.map[&1], "One");
+    }
+
+    #[cfg(feature = "lending")]
+    #[test]
+    fn outstanding_loan_blocks_commit() {
+        use crate::lending_wrap::LendingWrap;
+        use lending_library::LendingLibrary;
+
+        let mut lib = LendingLibrary::new();
+        lib.insert(0, "Zero".to_string());
+        let mut map: HashMap<i32, String> = HashMap::new();
+        let mut lending = LendingWrap::new(&mut lib);
+        lending.insert(1, "One".to_string());
+        let loan = lending.lend(&0).unwrap();
+        let mut plain = HashWrap::new(&mut map);
+        plain.insert(2, "Two".to_string());
+        let mut manager = TransactionManager::new();
+        manager.add_unchecked(plain);
+        manager.add(lending);
+        let manager = match manager.commit() {
+            Err((1, manager)) => manager,
+            _ => panic!("commit with a staged value lent out succeeded"),
+        };
+        drop(loan);
+        assert!(manager.commit().is_ok());
+        assert_eq!(map[&2], "Two");
+        assert!(lib.contains_key(&1));
+    }
+}
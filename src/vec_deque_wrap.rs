@@ -0,0 +1,196 @@
+use std::{
+    collections::{vec_deque, VecDeque},
+    marker::PhantomData,
+};
+
+use crate::{
+    hash_wrap::{commit_behavior, SpecDrop},
+    transaction::Transaction,
+};
+
+pub struct VecDequeWrap<'a, T, B = commit_behavior::PanicIfUnfinalised>
+where
+    VecDequeWrap<'a, T, B>: SpecDrop,
+    T: Clone,
+    B: commit_behavior::Behavior,
+{
+    inner: &'a mut VecDeque<T>,
+    copy: Option<VecDeque<T>>,
+    commit_behaviour: PhantomData<B>,
+    finalised: bool,
+}
+
+impl<'a, T> VecDequeWrap<'a, T>
+where
+    T: Clone,
+{
+    pub fn new(deque: &'a mut VecDeque<T>) -> Self {
+        VecDequeWrap {
+            inner: deque,
+            copy: None,
+            commit_behaviour: PhantomData,
+            finalised: false,
+        }
+    }
+}
+
+impl<'a, T, B> VecDequeWrap<'a, T, B>
+where
+    VecDequeWrap<'a, T, B>: SpecDrop,
+    T: Clone,
+    B: commit_behavior::Behavior,
+{
+    fn _commit(&mut self) {
+        if let Some(d) = self.copy.take() {
+            *self.inner = d;
+        }
+        self.finalised = true;
+    }
+
+    pub fn commit(mut self) {
+        self._commit()
+    }
+
+    fn _rollback(&mut self) {
+        self.copy = None;
+        self.finalised = true;
+    }
+
+    pub fn rollback(mut self) {
+        self._rollback()
+    }
+
+    fn view(&self) -> &VecDeque<T> {
+        match &self.copy {
+            Some(d) => d,
+            None => self.inner,
+        }
+    }
+
+    // As with VecWrap, the first edit stages a full copy of the deque and
+    // every later edit is made to it.
+    fn shadow(&mut self) -> &mut VecDeque<T> {
+        let inner = &*self.inner;
+        self.copy.get_or_insert_with(|| inner.clone())
+    }
+
+    pub fn len(&self) -> usize {
+        self.view().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.view().is_empty()
+    }
+
+    pub fn get(&self, idx: usize) -> Option<&T> {
+        self.view().get(idx)
+    }
+
+    pub fn front(&self) -> Option<&T> {
+        self.view().front()
+    }
+
+    pub fn back(&self) -> Option<&T> {
+        self.view().back()
+    }
+
+    pub fn iter(&self) -> vec_deque::Iter<'_, T> {
+        self.view().iter()
+    }
+
+    pub fn push_front(&mut self, v: T) {
+        self.shadow().push_front(v);
+    }
+
+    pub fn push_back(&mut self, v: T) {
+        self.shadow().push_back(v);
+    }
+
+    pub fn pop_front(&mut self) -> Option<T> {
+        if self.is_empty() {
+            None
+        } else {
+            self.shadow().pop_front()
+        }
+    }
+
+    pub fn pop_back(&mut self) -> Option<T> {
+        if self.is_empty() {
+            None
+        } else {
+            self.shadow().pop_back()
+        }
+    }
+}
+
+impl<'a, T, B> Transaction for VecDequeWrap<'a, T, B>
+where
+    VecDequeWrap<'a, T, B>: SpecDrop,
+    T: Clone,
+    B: commit_behavior::Behavior,
+{
+    fn commit(self) {
+        VecDequeWrap::commit(self)
+    }
+
+    fn rollback(self) {
+        VecDequeWrap::rollback(self)
+    }
+}
+
+impl_into_behavior! {
+    VecDequeWrap<'a, T> { take: copy; }
+    where { T: Clone }
+    into_implicit_commit => ImplicitCommit;
+    into_implicit_rollback => ImplicitRollback;
+    into_panicking => PanicIfUnfinalised;
+}
+
+impl_spec_drop! {
+    VecDequeWrap<'a, T> where { T: Clone }
+    ImplicitCommit => _commit;
+    ImplicitRollback => _rollback;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn get_deque() -> VecDeque<i32> {
+        vec![1, 2, 3].into_iter().collect()
+    }
+
+    #[test]
+    fn interleaved_rollback() {
+        let mut deque = get_deque();
+        let mut wrap = VecDequeWrap::new(&mut deque);
+        wrap.push_front(0);
+        assert_eq!(wrap.pop_back(), Some(3));
+        wrap.push_back(4);
+        assert_eq!(wrap.pop_front(), Some(0));
+        assert_eq!(wrap.pop_front(), Some(1));
+        wrap.push_front(-1);
+        assert_eq!(wrap.iter().cloned().collect::<Vec<_>>(), vec![-1, 2, 4]);
+        assert_eq!((wrap.front(), wrap.back()), (Some(&-1), Some(&4)));
+        wrap.rollback();
+        assert_eq!(deque, get_deque());
+    }
+
+    #[test]
+    fn queue_commit() {
+        let mut deque = get_deque();
+        let mut wrap = VecDequeWrap::new(&mut deque);
+        wrap.push_back(4);
+        assert_eq!(wrap.pop_front(), Some(1));
+        wrap.commit();
+        assert_eq!(deque, vec![2, 3, 4]);
+
+        let mut empty = VecDeque::new();
+        let mut wrap = VecDequeWrap::<i32>::new(&mut empty).into_implicit_commit();
+        assert_eq!(wrap.pop_back(), None);
+        assert_eq!(wrap.len(), 0);
+        wrap.push_front(7);
+        drop(wrap);
+        assert_eq!(empty, vec![7]);
+    }
+}
@@ -0,0 +1,214 @@
+use std::{
+    marker::PhantomData,
+    ops::{Index, IndexMut},
+    slice,
+};
+
+use crate::{
+    hash_wrap::{commit_behavior, SpecDrop},
+    transaction::Transaction,
+};
+
+pub struct VecWrap<'a, T, B = commit_behavior::PanicIfUnfinalised>
+where
+    VecWrap<'a, T, B>: SpecDrop,
+    T: Clone,
+    B: commit_behavior::Behavior,
+{
+    inner: &'a mut Vec<T>,
+    copy: Option<Vec<T>>,
+    commit_behaviour: PhantomData<B>,
+    finalised: bool,
+}
+
+impl<'a, T> VecWrap<'a, T>
+where
+    T: Clone,
+{
+    pub fn new(vec: &'a mut Vec<T>) -> Self {
+        VecWrap {
+            inner: vec,
+            copy: None,
+            commit_behaviour: PhantomData,
+            finalised: false,
+        }
+    }
+}
+
+impl<'a, T, B> VecWrap<'a, T, B>
+where
+    VecWrap<'a, T, B>: SpecDrop,
+    T: Clone,
+    B: commit_behavior::Behavior,
+{
+    fn _commit(&mut self) {
+        if let Some(v) = self.copy.take() {
+            *self.inner = v;
+        }
+        self.finalised = true;
+    }
+
+    pub fn commit(mut self) {
+        self._commit()
+    }
+
+    fn _rollback(&mut self) {
+        self.copy = None;
+        self.finalised = true;
+    }
+
+    pub fn rollback(mut self) {
+        self._rollback()
+    }
+
+    fn view(&self) -> &Vec<T> {
+        match &self.copy {
+            Some(v) => v,
+            None => self.inner,
+        }
+    }
+
+    // Positional edits don't compose well as a change log, so the first edit
+    // stages a full copy of the vector and every later edit is made to it.
+    fn shadow(&mut self) -> &mut Vec<T> {
+        let inner = &*self.inner;
+        self.copy.get_or_insert_with(|| inner.clone())
+    }
+
+    pub fn len(&self) -> usize {
+        self.view().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.view().is_empty()
+    }
+
+    pub fn get(&self, idx: usize) -> Option<&T> {
+        self.view().get(idx)
+    }
+
+    pub fn iter(&self) -> slice::Iter<'_, T> {
+        self.view().iter()
+    }
+
+    pub fn push(&mut self, v: T) {
+        self.shadow().push(v);
+    }
+
+    pub fn pop(&mut self) -> Option<T> {
+        if self.is_empty() {
+            None
+        } else {
+            self.shadow().pop()
+        }
+    }
+
+    pub fn insert(&mut self, idx: usize, v: T) {
+        self.shadow().insert(idx, v);
+    }
+
+    pub fn remove(&mut self, idx: usize) -> T {
+        self.shadow().remove(idx)
+    }
+}
+
+impl<'a, T, B> Index<usize> for VecWrap<'a, T, B>
+where
+    VecWrap<'a, T, B>: SpecDrop,
+    T: Clone,
+    B: commit_behavior::Behavior,
+{
+    type Output = T;
+
+    fn index(&self, index: usize) -> &Self::Output {
+        &self.view()[index]
+    }
+}
+
+impl<'a, T, B> IndexMut<usize> for VecWrap<'a, T, B>
+where
+    VecWrap<'a, T, B>: SpecDrop,
+    T: Clone,
+    B: commit_behavior::Behavior,
+{
+    fn index_mut(&mut self, index: usize) -> &mut Self::Output {
+        &mut self.shadow()[index]
+    }
+}
+
+impl<'a, T, B> Transaction for VecWrap<'a, T, B>
+where
+    VecWrap<'a, T, B>: SpecDrop,
+    T: Clone,
+    B: commit_behavior::Behavior,
+{
+    fn commit(self) {
+        VecWrap::commit(self)
+    }
+
+    fn rollback(self) {
+        VecWrap::rollback(self)
+    }
+}
+
+impl_into_behavior! {
+    VecWrap<'a, T> { take: copy; }
+    where { T: Clone }
+    into_implicit_commit => ImplicitCommit;
+    into_implicit_rollback => ImplicitRollback;
+    into_panicking => PanicIfUnfinalised;
+}
+
+impl_spec_drop! {
+    VecWrap<'a, T> where { T: Clone }
+    ImplicitCommit => _commit;
+    ImplicitRollback => _rollback;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn pushes_and_rollback() {
+        let mut vec = vec![1, 2, 3];
+        let mut wrap = VecWrap::new(&mut vec);
+        wrap.push(4);
+        wrap.push(5);
+        assert_eq!(wrap.len(), 5);
+        assert_eq!(wrap.pop(), Some(5));
+        wrap.insert(0, 0);
+        wrap.commit();
+        assert_eq!(vec, vec![0, 1, 2, 3, 4]);
+
+        let mut wrap = VecWrap::new(&mut vec);
+        assert_eq!(wrap.remove(1), 1);
+        assert_eq!(wrap.iter().cloned().collect::<Vec<_>>(), vec![0, 2, 3, 4]);
+        wrap.rollback();
+        assert_eq!(vec, vec![0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn index_assignment() {
+        let mut vec = vec![1, 2, 3];
+        let mut wrap = VecWrap::new(&mut vec);
+        assert_eq!(wrap[1], 2);
+        wrap[1] = 20;
+        assert_eq!(wrap.get(1), Some(&20));
+        drop(wrap.into_implicit_commit());
+        assert_eq!(vec, vec![1, 20, 3]);
+
+        let mut wrap = VecWrap::new(&mut vec).into_implicit_rollback();
+        wrap[0] = 10;
+        drop(wrap);
+        assert_eq!(vec, vec![1, 20, 3]);
+    }
+
+    #[test]
+    #[should_panic(expected = "without calling commit or rollback")]
+    fn drop_unfinalised() {
+        let mut vec = vec![1, 2, 3];
+        let mut wrap = VecWrap::new(&mut vec);
+        wrap.push(4);
+    }
+}